@@ -0,0 +1,271 @@
+// Compiled functions as .loxc files so programs can run without being
+// compiled again. All numbers are little endian:
+//
+//   file     = "LOXC" version:u16 function
+//   function = name arity:u8 up_value_count:u32 type:u8
+//              code:bytes locations:(count:u32 location*) constants:(count:u32 constant*)
+//   location = offset:u32 line:u32 column:u32, one for every run of code
+//              from the same place in the source
+//   name     = 0 | 1 string
+//   constant = 0 (nil) | 1 bool:u8 | 2 number:f64 | 3 string | 4 function
+//   string   = bytes, utf-8
+//   bytes    = length:u32 u8*
+use crate::chunk::{Chunk, Location};
+use crate::common::{FatPointer, Function, FunctionType, Obj, Value};
+use crate::memory::{self, Heap, HeapObj};
+use crate::value::ValueArray;
+
+const MAGIC: &[u8; 4] = b"LOXC";
+// bumped whenever the layout above or the meaning of the code changes
+const VERSION: u16 = 9;
+
+const NIL: u8 = 0;
+const BOOLEAN: u8 = 1;
+const NUMBER: u8 = 2;
+const STRING: u8 = 3;
+const FUNCTION: u8 = 4;
+
+pub(crate) fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub(crate) fn write(function: &Function) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    write_function(&mut bytes, function);
+    bytes
+}
+
+fn write_function(bytes: &mut Vec<u8>, function: &Function) {
+    match &function.name {
+        Some(name) => {
+            bytes.push(1);
+            write_string(bytes, name);
+        }
+        None => bytes.push(0),
+    }
+    bytes.push(function.arity);
+    write_u32(bytes, function.up_value_count);
+    bytes.push(match function.func_type {
+        FunctionType::Script => 0,
+        FunctionType::Closure => 1,
+        FunctionType::Method => 2,
+        FunctionType::Initializer => 3,
+    });
+
+    let chunk = &function.chunk;
+    write_u32(bytes, chunk.code.len());
+    bytes.extend_from_slice(&chunk.code);
+    write_u32(bytes, chunk.locations.len());
+    for (offset, location) in chunk.locations.iter() {
+        write_u32(bytes, *offset);
+        bytes.extend_from_slice(&location.line.to_le_bytes());
+        bytes.extend_from_slice(&location.column.to_le_bytes());
+    }
+    write_u32(bytes, chunk.constants.values.len());
+    for constant in chunk.constants.values.iter() {
+        match constant {
+            Value::Missing => bytes.push(NIL),
+            Value::Boolean(value) => {
+                bytes.push(BOOLEAN);
+                bytes.push(*value as u8);
+            }
+            Value::Number(value) => {
+                bytes.push(NUMBER);
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            Value::Obj(Obj::Str(value)) => {
+                bytes.push(STRING);
+                write_string(bytes, value);
+            }
+            Value::Obj(Obj::Fun(value)) => {
+                bytes.push(FUNCTION);
+                write_function(bytes, unsafe { &**value });
+            }
+            // the compiler only ever creates the constants above
+            Value::Obj(obj) => unreachable!("{} can't be a constant", obj),
+        }
+    }
+}
+
+fn write_string(bytes: &mut Vec<u8>, value: &FatPointer) {
+    write_u32(bytes, value.size);
+    bytes.extend_from_slice(memory::read_string(value.ptr, value.size).as_bytes());
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+// Strings are interned in `heap` the same way the compiler does it, so the
+// function can run as if it had just been compiled.
+pub(crate) fn read(bytes: &[u8], heap: &mut Heap) -> Result<Function, String> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("Not a compiled lox file.".to_string());
+    }
+    let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+    if version != VERSION {
+        return Err(format!(
+            "Compiled with bytecode version {} but version {} is supported.",
+            version, VERSION
+        ));
+    }
+    let function = reader.function(heap)?;
+    if reader.offset != bytes.len() {
+        return Err(reader.error("unexpected bytes after the script"));
+    }
+    Ok(function)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: &str) -> String {
+        format!(
+            "Corrupt compiled file at byte {}: {}.",
+            self.offset, message
+        )
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.offset < count {
+            return Err(self.error("unexpected end of file"));
+        }
+        let taken = &self.bytes[self.offset..self.offset + count];
+        self.offset += count;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn string(&mut self, heap: &mut Heap) -> Result<FatPointer, String> {
+        let length = self.u32()?;
+        let bytes = self.take(length)?;
+        match std::str::from_utf8(bytes) {
+            Ok(value) => Ok(heap.intern(value)),
+            Err(_) => Err(self.error("string is not utf-8")),
+        }
+    }
+
+    fn function(&mut self, heap: &mut Heap) -> Result<Function, String> {
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string(heap)?),
+            _ => return Err(self.error("invalid function name")),
+        };
+        let arity = self.u8()?;
+        let up_value_count = self.u32()?;
+        let func_type = match self.u8()? {
+            0 => FunctionType::Script,
+            1 => FunctionType::Closure,
+            2 => FunctionType::Method,
+            3 => FunctionType::Initializer,
+            _ => return Err(self.error("invalid function type")),
+        };
+
+        let mut chunk = Chunk::init();
+        let code_length = self.u32()?;
+        chunk.code = self.take(code_length)?.to_vec();
+        let location_count = self.u32()?;
+        for _ in 0..location_count {
+            let offset = self.u32()?;
+            let line = self.u32()? as u32;
+            let column = self.u32()? as u32;
+            // runs start at the first byte and each one after the one before
+            let expected = match chunk.locations.last() {
+                Some((previous, _)) => offset > *previous,
+                None => offset == 0,
+            };
+            if !expected || offset >= chunk.code.len() {
+                return Err(self.error("invalid location of code"));
+            }
+            chunk.locations.push((offset, Location { line, column }));
+        }
+        if chunk.locations.is_empty() && !chunk.code.is_empty() {
+            return Err(self.error("every byte of code needs a location"));
+        }
+        let constant_count = self.u32()?;
+        chunk.constants = ValueArray::init();
+        for _ in 0..constant_count {
+            let constant = match self.u8()? {
+                NIL => Value::Missing,
+                BOOLEAN => Value::from(self.u8()? != 0),
+                NUMBER => Value::from(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+                STRING => Value::from(Obj::Str(self.string(heap)?)),
+                FUNCTION => {
+                    let function = self.function(heap)?;
+                    Value::from(Obj::Fun(heap.allocate(function, HeapObj::Function)))
+                }
+                _ => return Err(self.error("invalid constant type")),
+            };
+            chunk.constants.append(constant);
+        }
+
+        Ok(Function {
+            arity,
+            up_value_count,
+            chunk,
+            name,
+            func_type,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::scanner::Scanner;
+
+    fn compile(source: &str, heap: &mut Heap) -> Function {
+        let scanner = Scanner::init(0, 0, vec![]);
+        let mut compiler = Compiler::init(scanner, heap);
+        Into::<Function>::into(compiler.compile(source.to_string()).unwrap())
+    }
+
+    #[test]
+    fn can_read_what_was_written() {
+        let mut heap = Heap::init();
+        let source = "fun add(a, b) { return a + b; } class C { init() { this.s = \"s\"; } } \
+                      print add(1.5, 2);";
+        let function = compile(source, &mut heap);
+        let bytes = write(&function);
+
+        let read_back = read(&bytes, &mut heap).unwrap();
+        assert_eq!(write(&read_back), bytes);
+        assert_eq!(read_back.chunk.code, function.chunk.code);
+        assert_eq!(read_back.chunk.locations, function.chunk.locations);
+        // strings are interned so names point to the same memory as before
+        let name = |function: &Function| match function.chunk.constants.get(1) {
+            Value::Obj(Obj::Fun(nested)) => unsafe { &*nested }.name.clone(),
+            constant => panic!("Expected function but got {}", constant),
+        };
+        assert_eq!(name(&read_back), name(&function));
+        assert!(name(&read_back).is_some());
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        let mut heap = Heap::init();
+        let bytes = write(&compile("print 1;", &mut heap));
+
+        assert!(read(b"print 1;", &mut heap).is_err());
+        assert!(read(&bytes[..bytes.len() - 1], &mut heap).is_err());
+        let mut newer = bytes.clone();
+        newer[4] = 10;
+        assert_eq!(
+            read(&newer, &mut heap).unwrap_err(),
+            "Compiled with bytecode version 10 but version 9 is supported."
+        );
+    }
+}
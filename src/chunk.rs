@@ -1,148 +1,268 @@
-use crate::common::{OpCode, Value};
+use crate::common::{Obj, OpCode, Value};
 use crate::debug;
+use crate::disassembler;
 use crate::value::{self, ValueArray};
 extern crate num;
+
+// long operands are 24 bit little endian so compiled code reads the same
+// on every machine
+const LONG_OPERAND_BYTES: usize = 3;
+// most constants, locals or upvalues a function can have, more is a
+// compile error
+pub(crate) const MAX_INDEX: usize = (1 << (LONG_OPERAND_BYTES * 8)) - 1;
+
+// where in the source code came from, columns start at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Location {
+    pub(crate) line: u32,
+    pub(crate) column: u32,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct Chunk {
     pub code: Vec<u8>,
     pub constants: value::ValueArray,
-    pub lines: Vec<u32>,
+    // run length encoded, the offset of the first byte of every run of
+    // bytes compiled from the same location
+    pub locations: Vec<(usize, Location)>,
 }
 
-impl<'a> Chunk {
+impl Chunk {
     pub(crate) fn init() -> Chunk {
         Chunk {
             code: vec![],
             constants: ValueArray::init(),
-            lines: vec![],
+            locations: vec![],
         }
     }
 
-    pub(crate) fn write_chunk(&mut self, byte: u8, line: u32) {
+    pub(crate) fn write_chunk(&mut self, byte: u8, location: Location) {
+        if self.locations.last().map(|(_, last)| *last) != Some(location) {
+            self.locations.push((self.code.len(), location));
+        }
         self.code.push(byte);
-        self.lines.push(line);
     }
 
-    pub(crate) fn add_constant(&mut self, value: Value) -> usize {
-        self.constants.append(value);
-        self.constants.count()
+    // location of the byte at `offset`
+    pub(crate) fn location_of(&self, offset: usize) -> Location {
+        let run = self.locations.partition_point(|(start, _)| *start <= offset);
+        self.locations[run - 1].1
     }
 
-    // version of write_chunk
-    pub(crate) fn write_constant(&mut self, value: Value, line: u32) -> usize {
-        let index = self.add_constant(value);
-        // for any index constant that doesn't fit in u8, we store all bytes
-        if index <= 255 {
-            self.write_chunk(OpCode::Constant as u8, line);
-        } else {
-            self.write_chunk(OpCode::ConstantLong as u8, line);
+    // None when the chunk already has as many constants as an operand can
+    // index
+    pub(crate) fn add_constant(&mut self, value: Value) -> Option<usize> {
+        if self.constants.values.len() > MAX_INDEX {
+            return None;
         }
-        self.write_index(index, line);
-        index
+        self.constants.append(value);
+        Some(self.constants.count())
     }
 
-    pub(crate) fn write_index(&mut self, index: usize, line: u32) {
-        if index <= 255 {
-            self.write_chunk(index as u8, line);
-        } else {
-            let bytes = index.to_ne_bytes();
-            for byte in bytes.iter() {
-                self.write_chunk(*byte, line);
-            }
+    // writes `opcode` with `index` as its operand, or the long variant of
+    // `opcode` for any index that doesn't fit in u8
+    pub(crate) fn write_indexed(&mut self, opcode: OpCode, index: usize, location: Location) {
+        let long = index > u8::MAX as usize;
+        let opcode = if long { opcode.long_variant() } else { opcode };
+        self.write_chunk(opcode as u8, location);
+        self.write_operand(index, long, location);
+    }
+
+    pub(crate) fn write_operand(&mut self, index: usize, long: bool, location: Location) {
+        for byte in operand_bytes(index, long) {
+            self.write_chunk(byte, location);
         }
     }
 
+    // disassembly is written to the trace sink, callers decide when it's wanted.
     pub(crate) fn disassemble_chunk(&self, name: &str) {
-        debug::info(format!("=== {} === ", name));
-        let mut offset: usize = 0;
-        while offset < self.code.len() {
-            debug::info(format!("{:04}", offset));
-            // if its on same line
-            if offset > 0 && self.lines.get(offset) == self.lines.get(offset - 1) {
-                debug::info(" | ".to_string());
-            } else {
-                debug::info(format!("Line: {}", self.lines.get(offset).unwrap()));
-            }
-            let instruction = self.code.get(offset).unwrap();
-            offset = self.handle_instruction(instruction, offset);
-        }
+        debug::trace(disassembler::listing(self, name));
     }
 
-    pub fn handle_instruction(&self, instruction: &u8, offset: usize) -> usize {
-        let opcode = num::FromPrimitive::from_u8(*instruction);
-        match opcode {
-            Some(OpCode::Return)
-            | Some(OpCode::Negate)
-            | Some(OpCode::Add)
-            | Some(OpCode::Subtract)
-            | Some(OpCode::Multiply)
-            | Some(OpCode::False)
-            | Some(OpCode::True)
-            | Some(OpCode::Nil)
-            | Some(OpCode::Not)
-            | Some(OpCode::Greater)
-            | Some(OpCode::Less)
-            | Some(OpCode::Equal)
-            | Some(OpCode::Print)
-            | Some(OpCode::DefineGlobalVariable)
-            | Some(OpCode::GetGlobalVariable)
-            | Some(OpCode::SetGlobalVariable)
-            | Some(OpCode::GetLocalVariable)
+    // Reads the instruction starting at `offset`. The code is trusted to be
+    // well formed, it either comes from the compiler or passed the verifier.
+    pub(crate) fn decode(&self, offset: usize) -> Instruction {
+        let byte = self.code[offset];
+        let opcode: Option<OpCode> = num::FromPrimitive::from_u8(byte);
+        let long = opcode.is_some_and(OpCode::is_long);
+        let width = operand_width(long);
+        let operand = || read_operand(&self.code[offset + 1..], long);
+        let (operand, next) = match opcode.map(OpCode::short_variant) {
+            Some(OpCode::GetLocalVariable)
             | Some(OpCode::SetLocalVariable)
-            | Some(OpCode::Pop)
+            | Some(OpCode::GetUpValue)
+            | Some(OpCode::SetUpValue)
             | Some(OpCode::Call)
-            | Some(OpCode::Closure)
-            | Some(OpCode::Divide) => {
-                debug::debug(format!("opcode: {:?}", opcode.unwrap()), true);
-            }
-            Some(OpCode::Jump) | Some(OpCode::JumpIfFalse) | Some(OpCode::Loop) => {
-                self.jump_instruction(opcode.unwrap(), offset);
-                return offset + 3; // 1 byte for opcode 2 for the jump offset
+            | Some(OpCode::BuildList)
+            | Some(OpCode::BuildMap) => (Operand::Index(operand()), offset + 1 + width),
+            Some(opcode) if reads_constant(opcode) => {
+                (Operand::Constant(operand()), offset + 1 + width)
             }
-            Some(OpCode::Constant) => {
-                let constant_index = self.code.get(offset + 1).unwrap();
-                self.print_debug_info(OpCode::Constant, *constant_index as usize);
-                // return 1 byte of constant_index + 1 byte of opcode
-                return offset + 2;
+            // long jumps have four bytes for the offset
+            Some(OpCode::Jump) | Some(OpCode::JumpIfFalse) => {
+                let width = if long { 4 } else { 2 };
+                let after = offset + 1 + width;
+                (Operand::Jump(after + self.get_offset(offset, width)), after)
             }
-            Some(OpCode::ConstantLong) => {
-                let mut constant_index_bytes = [0, 0, 0, 0, 0, 0, 0, 0];
-                // our long constant index is usize which is 8 bytes
-                for i in 1..=8 {
-                    constant_index_bytes[i - 1] = *self.code.get(i + offset).unwrap();
-                }
-                let constant_index = usize::from_ne_bytes(constant_index_bytes);
-                self.print_debug_info(OpCode::ConstantLong, constant_index);
-                // return 8 bytes of constant_index + 1 byte of opcode
-                return offset + 9;
+            Some(OpCode::Loop) => {
+                let width = if long { 4 } else { 2 };
+                let after = offset + 1 + width;
+                (Operand::Jump(after - self.get_offset(offset, width)), after)
             }
-            _ => {
-                debug::info(format!("Unknown instruction: {:?}", opcode));
+            Some(OpCode::Closure) => {
+                let constant = operand();
+                let up_value_count = match self.constants.get(constant) {
+                    Value::Obj(Obj::Fun(function)) => unsafe { &*function }.up_value_count,
+                    _ => 0,
+                };
+                // every captured variable is an is_local byte and its index
+                let captures = (0..up_value_count)
+                    .map(|i| {
+                        let capture = offset + 1 + width + i * (1 + width);
+                        let index = read_operand(&self.code[capture + 1..], long);
+                        (self.code[capture] == 1, index)
+                    })
+                    .collect();
+                let next = offset + 1 + width + up_value_count * (1 + width);
+                (Operand::Closure(constant, captures), next)
             }
+            _ => (Operand::None, offset + 1),
+        };
+        Instruction {
+            offset,
+            location: self.location_of(offset),
+            byte,
+            opcode,
+            operand,
+            next,
+        }
+    }
+
+    // every instruction of the chunk in order.
+    pub(crate) fn instructions(&self) -> Vec<Instruction> {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let instruction = self.decode(offset);
+            offset = instruction.next;
+            instructions.push(instruction);
         }
-        offset + 1
+        instructions
     }
 
-    fn jump_instruction(&self, instruction: OpCode, offset: usize) {
-        debug::info(format!("opcode: {:?}", instruction));
-        debug::info(format!("with jump: {:?}", self.get_offset(offset)));
+    // jump distance of the jump or loop instruction at `offset`, stored in
+    // the `width` bytes after the opcode most significant first
+    pub(crate) fn get_offset(&self, offset: usize, width: usize) -> usize {
+        self.code[offset + 1..offset + 1 + width]
+            .iter()
+            .fold(0, |jump, byte| jump << 8 | *byte as usize)
+    }
+}
+
+pub(crate) struct Instruction {
+    pub(crate) offset: usize,
+    pub(crate) location: Location,
+    pub(crate) byte: u8,
+    // None when `byte` isn't a known opcode
+    pub(crate) opcode: Option<OpCode>,
+    pub(crate) operand: Operand,
+    // offset of the instruction after this one
+    pub(crate) next: usize,
+}
+
+pub(crate) enum Operand {
+    None,
+    // local slot, upvalue index or argument count
+    Index(usize),
+    // index into the constants of the chunk
+    Constant(usize),
+    // offset the jump lands on
+    Jump(usize),
+    // function constant and whether each capture is a local and its index
+    Closure(usize, Vec<(bool, usize)>),
+}
+
+// whether the only operand of `opcode` is the index of a constant, the name
+// of the variable, class, method or property is a constant. Closures have
+// their function as a constant too but more operands after it.
+pub(crate) fn reads_constant(opcode: OpCode) -> bool {
+    matches!(
+        opcode.short_variant(),
+        OpCode::Constant
+            | OpCode::DefineGlobalVariable
+            | OpCode::GetGlobalVariable
+            | OpCode::SetGlobalVariable
+            | OpCode::Class
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::Method
+            | OpCode::GetSuper
+    )
+}
+
+pub(crate) fn operand_width(long: bool) -> usize {
+    if long {
+        LONG_OPERAND_BYTES
+    } else {
+        1
+    }
+}
+
+pub(crate) fn operand_bytes(index: usize, long: bool) -> Vec<u8> {
+    index.to_le_bytes()[..operand_width(long)].to_vec()
+}
+
+// the operand at the start of `bytes`
+pub(crate) fn read_operand(bytes: &[u8], long: bool) -> usize {
+    bytes[..operand_width(long)]
+        .iter()
+        .rev()
+        .fold(0, |index, byte| index << 8 | *byte as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(line: u32, column: u32) -> Location {
+        Location { line, column }
     }
 
-    fn get_offset(&self, offset: usize) -> u16 {
-        let offset_bytes: [u8; 2] = [
-            self.code[(offset + 2) as usize],
-            self.code[(offset + 1) as usize],
-        ];
-        println!("offset bytes: {:?}", offset_bytes);
-        // adding 2 because we read offset bytes
-        u16::from_ne_bytes(offset_bytes)
+    #[test]
+    fn long_operands_are_three_bytes_little_endian() {
+        let mut chunk = Chunk::init();
+        chunk.write_indexed(OpCode::GetGlobalVariable, 7, at(1, 1));
+        chunk.write_indexed(OpCode::GetGlobalVariable, 0x012345, at(1, 1));
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::GetGlobalVariable as u8,
+                7,
+                OpCode::GetGlobalVariableLong as u8,
+                0x45,
+                0x23,
+                0x01
+            ]
+        );
+        let instruction = chunk.decode(2);
+        assert!(matches!(instruction.operand, Operand::Constant(0x012345)));
+        assert_eq!(instruction.next, chunk.code.len());
     }
 
-    fn print_debug_info(&self, opcode: OpCode, constant_index: usize) {
-        debug::info(format!("opcode: {:?}", opcode));
-        debug::info(format!("constant index: {}", constant_index));
-        //TODO: I am not sure if converting u8 to size here is fine or not
-        let value = self.constants.get(constant_index as usize);
-        debug::print_value(&value, true);
+    #[test]
+    fn stores_one_location_per_run_of_bytes() {
+        let mut chunk = Chunk::init();
+        chunk.write_indexed(OpCode::Constant, 0, at(1, 7));
+        chunk.write_chunk(OpCode::Add as u8, at(1, 5));
+        chunk.write_chunk(OpCode::Print as u8, at(1, 1));
+        chunk.write_chunk(OpCode::Nil as u8, at(2, 1));
+        chunk.write_chunk(OpCode::Return as u8, at(2, 1));
+        assert_eq!(
+            chunk.locations,
+            vec![(0, at(1, 7)), (2, at(1, 5)), (3, at(1, 1)), (4, at(2, 1))]
+        );
+        assert_eq!(chunk.location_of(1), at(1, 7));
+        assert_eq!(chunk.location_of(3), at(1, 1));
+        assert_eq!(chunk.location_of(5), at(2, 1));
     }
 }
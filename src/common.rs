@@ -1,13 +1,15 @@
 use colored::Color;
 use num_derive::FromPrimitive;
 use rand::prelude::*;
-use std::fmt::Debug;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt::{Debug, Display};
 
-use crate::{chunk::Chunk, hasher, memory};
+use crate::{chunk::Chunk, hash_map::Table, hasher, memory, vm::VM};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(u8)]
-#[derive(FromPrimitive)]
+#[derive(FromPrimitive, Eq, PartialEq)]
 pub(crate) enum OpCode {
     Return = 1,
     Constant = 2,
@@ -38,6 +40,124 @@ pub(crate) enum OpCode {
     Closure = 27,
     SetUpValue = 28,
     GetUpValue = 29,
+    CloseUpValue = 30,
+    Class = 31,
+    GetProperty = 32,
+    SetProperty = 33,
+    Method = 34,
+    Inherit = 35,
+    GetSuper = 36,
+    GreaterEqual = 37,
+    LessEqual = 38,
+    NotEqual = 39,
+    // jumps with a four byte offset
+    JumpLong = 40,
+    JumpIfFalseLong = 41,
+    LoopLong = 42,
+    // the same as the opcodes above but with long operands, for functions
+    // with more than 256 constants, locals or upvalues
+    DefineGlobalVariableLong = 43,
+    GetGlobalVariableLong = 44,
+    SetGlobalVariableLong = 45,
+    GetLocalVariableLong = 46,
+    SetLocalVariableLong = 47,
+    GetUpValueLong = 48,
+    SetUpValueLong = 49,
+    ClassLong = 50,
+    GetPropertyLong = 51,
+    SetPropertyLong = 52,
+    MethodLong = 53,
+    GetSuperLong = 54,
+    ClosureLong = 55,
+    // the operand is the number of items taken off the stack
+    BuildList = 56,
+    BuildListLong = 57,
+    GetIndex = 58,
+    SetIndex = 59,
+    // the operand is the number of key value pairs taken off the stack
+    BuildMap = 60,
+    BuildMapLong = 61,
+    Modulo = 62,
+    FloorDivide = 63,
+    Power = 64,
+}
+
+// pairs of opcodes with short and long operands
+const LONG_VARIANTS: [(OpCode, OpCode); 19] = [
+    (OpCode::Constant, OpCode::ConstantLong),
+    (OpCode::Jump, OpCode::JumpLong),
+    (OpCode::JumpIfFalse, OpCode::JumpIfFalseLong),
+    (OpCode::Loop, OpCode::LoopLong),
+    (OpCode::DefineGlobalVariable, OpCode::DefineGlobalVariableLong),
+    (OpCode::GetGlobalVariable, OpCode::GetGlobalVariableLong),
+    (OpCode::SetGlobalVariable, OpCode::SetGlobalVariableLong),
+    (OpCode::GetLocalVariable, OpCode::GetLocalVariableLong),
+    (OpCode::SetLocalVariable, OpCode::SetLocalVariableLong),
+    (OpCode::GetUpValue, OpCode::GetUpValueLong),
+    (OpCode::SetUpValue, OpCode::SetUpValueLong),
+    (OpCode::Class, OpCode::ClassLong),
+    (OpCode::GetProperty, OpCode::GetPropertyLong),
+    (OpCode::SetProperty, OpCode::SetPropertyLong),
+    (OpCode::Method, OpCode::MethodLong),
+    (OpCode::GetSuper, OpCode::GetSuperLong),
+    (OpCode::Closure, OpCode::ClosureLong),
+    (OpCode::BuildList, OpCode::BuildListLong),
+    (OpCode::BuildMap, OpCode::BuildMapLong),
+];
+
+impl OpCode {
+    // the same opcode with long operands, itself if there is none
+    pub(crate) fn long_variant(self) -> OpCode {
+        LONG_VARIANTS
+            .iter()
+            .find(|(short, _)| *short == self)
+            .map_or(self, |(_, long)| *long)
+    }
+
+    // the same opcode with short operands, itself if there is none
+    pub(crate) fn short_variant(self) -> OpCode {
+        LONG_VARIANTS
+            .iter()
+            .find(|(_, long)| *long == self)
+            .map_or(self, |(short, _)| *short)
+    }
+
+    // the vm asks for every indexed instruction, so no table lookup here
+    pub(crate) fn is_long(self) -> bool {
+        matches!(
+            self,
+            OpCode::ConstantLong
+                | OpCode::JumpLong
+                | OpCode::JumpIfFalseLong
+                | OpCode::LoopLong
+                | OpCode::DefineGlobalVariableLong
+                | OpCode::GetGlobalVariableLong
+                | OpCode::SetGlobalVariableLong
+                | OpCode::GetLocalVariableLong
+                | OpCode::SetLocalVariableLong
+                | OpCode::GetUpValueLong
+                | OpCode::SetUpValueLong
+                | OpCode::ClassLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::MethodLong
+                | OpCode::GetSuperLong
+                | OpCode::ClosureLong
+                | OpCode::BuildListLong
+                | OpCode::BuildMapLong
+        )
+    }
+}
+
+// remainder with the sign of the divisor so that
+// left == (left // right) * right + left % right
+pub(crate) fn modulo(left: f64, right: f64) -> f64 {
+    let remainder = left % right;
+    if remainder != 0.0 && (remainder < 0.0) != (right < 0.0) {
+        remainder + right
+    } else {
+        remainder
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -71,25 +191,34 @@ impl Value {
 
     #[inline]
     pub fn is_obj_string(&self) -> bool {
-        return match self {
-            Value::Obj(obj) => unsafe { obj.is_string() },
+        match self {
+            Value::Obj(obj) => obj.is_string(),
             _ => false,
-        };
+        }
     }
 }
 
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
-        if matches!(self, _other) {
-            return match (self, other) {
-                (Value::Boolean(l), Value::Boolean(r)) => l == r,
-                (Value::Number(l), Value::Number(r)) => l == r,
-                (Value::Missing, Value::Missing) => true,
-                (Value::Obj(l), Value::Obj(r)) => l == r,
-                _ => false,
-            };
+        match (self, other) {
+            (Value::Boolean(l), Value::Boolean(r)) => l == r,
+            (Value::Number(l), Value::Number(r)) => l == r,
+            (Value::Missing, Value::Missing) => true,
+            (Value::Obj(l), Value::Obj(r)) => l == r,
+            _ => false,
+        }
+    }
+}
+
+// formats value the way lox prints it
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Boolean(value) => write!(f, "{}", value),
+            Value::Number(value) => write!(f, "{}", value),
+            Value::Missing => write!(f, "nil"),
+            Value::Obj(obj) => write!(f, "{}", obj),
         }
-        false
     }
 }
 
@@ -111,10 +240,9 @@ impl From<Obj> for Value {
     }
 }
 
-
-impl Into<bool> for &Value {
-    fn into(self) -> bool {
-        match self {
+impl From<&Value> for bool {
+    fn from(value: &Value) -> Self {
+        match value {
             Value::Boolean(value) => *value,
             //@todo @pending check if it should be false this can be wrong in most cases
             // may be we should throw error
@@ -123,9 +251,9 @@ impl Into<bool> for &Value {
     }
 }
 
-impl Into<f64> for &Value {
-    fn into(self) -> f64 {
-        match self {
+impl From<&Value> for f64 {
+    fn from(value: &Value) -> Self {
+        match value {
             Value::Number(value) => *value,
             //@todo @pending check if it should be false this can be wrong in most cases
             // may be we should throw error
@@ -134,9 +262,9 @@ impl Into<f64> for &Value {
     }
 }
 
-impl Into<Obj> for &Value {
-    fn into(self) -> Obj {
-        match self {
+impl From<&Value> for Obj {
+    fn from(value: &Value) -> Self {
+        match value {
             Value::Obj(value) => value.clone(),
             //@todo @pending check if it should be false this can be wrong in most cases
             // may be we should throw error
@@ -145,9 +273,9 @@ impl Into<Obj> for &Value {
     }
 }
 
-impl Into<FatPointer> for &Value {
-    fn into(self) -> FatPointer {
-        match self {
+impl From<&Value> for FatPointer {
+    fn from(value: &Value) -> Self {
+        match value {
             Value::Obj(obj) => Into::<FatPointer>::into(obj.clone()),
             //@todo @pending check if it should be false this can be wrong in most cases
             // may be we should throw error
@@ -163,28 +291,37 @@ pub(crate) struct FatPointer {
     pub(crate) hash: u32,
 }
 
-
 impl PartialEq for FatPointer {
     fn eq(&self, other: &Self) -> bool {
-        if matches!(self, _other) {
-           return self.ptr == other.ptr && self.size == other.size && self.hash == other.hash;
-        }
-        false
+        self.ptr == other.ptr && self.size == other.size && self.hash == other.hash
     }
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct Function {
     pub(crate) arity: u8,
+    pub(crate) up_value_count: usize,
     pub(crate) chunk: Chunk,
     pub(crate) name: Option<FatPointer>,
     pub(crate) func_type: FunctionType,
 }
 
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) if !matches!(self.func_type, FunctionType::Script) => {
+                write!(f, "<fn {}>", memory::read_string(name.ptr, name.size))
+            }
+            _ => write!(f, "<script>"),
+        }
+    }
+}
+
 impl Function {
     pub(crate) fn new_function(fun_type: FunctionType) -> Function {
         Function {
             arity: 0,
+            up_value_count: 0,
             chunk: Chunk::init(),
             name: None,
             func_type: fun_type,
@@ -194,17 +331,164 @@ impl Function {
 
 #[derive(Debug, Clone)]
 pub(crate) enum FunctionType {
-    Function,
     Script,
     Closure,
+    Method,
+    Initializer,
+}
+
+// A captured variable. While the variable still lives on the VM stack the
+// cell only remembers its stack slot, once the slot goes out of scope the
+// value is moved into the cell so closures can keep using it.
+#[derive(Debug, Clone)]
+pub(crate) enum UpValueCell {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Closure {
+    pub(crate) function: *mut Function,
+    pub(crate) up_values: Vec<*mut UpValueCell>,
+}
+
+impl Closure {
+    pub(crate) fn new(function: *mut Function, up_values: Vec<*mut UpValueCell>) -> Closure {
+        Closure {
+            function,
+            up_values,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Class {
+    pub(crate) name: FatPointer,
+    pub(crate) methods: Table<Value>,
+}
+
+impl Class {
+    pub(crate) fn new(name: FatPointer) -> Class {
+        Class {
+            name,
+            methods: Table::init(8),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Instance {
+    pub(crate) class: *mut Class,
+    pub(crate) fields: Table<Value>,
+}
+
+impl Instance {
+    pub(crate) fn new(class: *mut Class) -> Instance {
+        Instance {
+            class,
+            fields: Table::init(8),
+        }
+    }
+}
+
+// method looked up on an instance, remembers the instance so `this`
+// is still bound when it's called later.
+#[derive(Debug)]
+pub(crate) struct BoundMethod {
+    pub(crate) receiver: Value,
+    pub(crate) method: *mut Closure,
+}
+
+impl BoundMethod {
+    pub(crate) fn new(receiver: Value, method: *mut Closure) -> BoundMethod {
+        BoundMethod { receiver, method }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct List {
+    pub(crate) items: Vec<Value>,
+}
+
+impl List {
+    pub(crate) fn new(items: Vec<Value>) -> List {
+        List { items }
+    }
+
+    // position of `index` in the list, it has to be a whole number below
+    // `len` or `len` itself when `end` can be used, like for inserting
+    pub(crate) fn position(&self, index: &Value, end: bool) -> Result<usize, String> {
+        let len = self.items.len();
+        match index {
+            Value::Number(number) if number.fract() == 0.0 => {
+                if *number >= 0.0 && (*number < len as f64 || (end && *number == len as f64)) {
+                    Ok(*number as usize)
+                } else {
+                    Err(format!(
+                        "Index {} is out of bounds for a list of length {}.",
+                        number, len
+                    ))
+                }
+            }
+            _ => Err("List index must be a whole number.".to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Map {
+    pub(crate) entries: Table<Value, Value>,
+}
+
+impl Map {
+    pub(crate) fn new(entries: Table<Value, Value>) -> Map {
+        Map { entries }
+    }
+
+    // keys can't change after they are hashed, so no lists, maps or instances
+    pub(crate) fn check_key(key: &Value) -> Result<(), String> {
+        match key {
+            Value::Number(number) if number.is_nan() => Err("Map keys can't be NaN.".to_string()),
+            Value::Boolean(_) | Value::Number(_) | Value::Missing | Value::Obj(Obj::Str(_)) => Ok(()),
+            _ => Err("Only strings, numbers, booleans and nil can be map keys.".to_string()),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &Value) -> Result<Value, String> {
+        match self.entries.get(key.clone()) {
+            Some(value) => Ok(value.clone()),
+            None => Err(format!("Undefined key '{}'.", key)),
+        }
+    }
+
+    pub(crate) fn set(&mut self, key: Value, value: Value) -> Result<(), String> {
+        Map::check_key(&key)?;
+        self.entries.insert(key, value);
+        Ok(())
+    }
+}
+
+// rust function callable from lox, errors are reported as runtime errors.
+pub(crate) type NativeFn = fn(vm: &mut VM, args: &[Value]) -> Result<Value, String>;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Native {
+    pub(crate) name: &'static str,
+    pub(crate) arity: u8,
+    pub(crate) function: NativeFn,
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum Obj {
     Str(FatPointer),
-    Fun(Function),
-    Closure(Box<Obj>),
-    Nil,
+    Fun(*mut Function),
+    Closure(*mut Closure),
+    Class(*mut Class),
+    Instance(*mut Instance),
+    BoundMethod(*mut BoundMethod),
+    Native(Native),
+    List(*mut List),
+    Map(*mut Map),
 }
 
 impl Obj {
@@ -212,31 +496,81 @@ impl Obj {
     pub fn is_string(&self) -> bool {
         matches!(self, Obj::Str(_))
     }
+}
 
-    #[inline]
-    pub fn is_nil(&self) -> bool {
-        matches!(self, Obj::Nil)
+impl PartialEq for Obj {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Obj::Str(l), Obj::Str(r)) => l == r,
+            (Obj::Fun(l), Obj::Fun(r)) => std::ptr::eq(*l, *r),
+            (Obj::Closure(l), Obj::Closure(r)) => std::ptr::eq(*l, *r),
+            (Obj::Class(l), Obj::Class(r)) => std::ptr::eq(*l, *r),
+            (Obj::Instance(l), Obj::Instance(r)) => std::ptr::eq(*l, *r),
+            (Obj::BoundMethod(l), Obj::BoundMethod(r)) => std::ptr::eq(*l, *r),
+            (Obj::Native(l), Obj::Native(r)) => std::ptr::fn_addr_eq(l.function, r.function),
+            (Obj::List(l), Obj::List(r)) => std::ptr::eq(*l, *r),
+            (Obj::Map(l), Obj::Map(r)) => std::ptr::eq(*l, *r),
+            _ => false,
+        }
     }
+}
 
-    pub fn get_func_chunk(&mut self) -> &mut Chunk {
-        match self {
-            Obj::Fun(function) => &mut function.chunk,
-            //@todo @pending check if it should be false this can be wrong in most cases
-            // may be we should throw error
-            _ => panic!("Not able to convert to function from object"),
-        }
+thread_local! {
+    // lists and maps which are being printed right now, one containing
+    // itself would otherwise be printed forever
+    static PRINTING: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+}
+
+// returns None if `ptr` is already being printed further up, `print`
+// runs with `ptr` marked so nested references to it are caught
+fn print_once(ptr: usize, print: impl FnOnce() -> String) -> Option<String> {
+    if !PRINTING.with(|printing| printing.borrow_mut().insert(ptr)) {
+        return None;
     }
+    let printed = print();
+    PRINTING.with(|printing| printing.borrow_mut().remove(&ptr));
+    Some(printed)
 }
 
-impl PartialEq for Obj {
-    fn eq(&self, other: &Self) -> bool {
-        if matches!(self, _other) {
-            return match (self, other) {
-                (Obj::Str(l), Obj::Str(r)) => l == r,
-                _ => false,
-            };
+impl Display for Obj {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Obj::Str(ptr) => write!(f, "{}", memory::read_string(ptr.ptr, ptr.size)),
+            Obj::Fun(function) => write!(f, "{}", unsafe { &**function }),
+            Obj::Closure(closure) => write!(f, "{}", unsafe { &*(**closure).function }),
+            Obj::Class(class) => {
+                let name = unsafe { &(**class).name };
+                write!(f, "{}", memory::read_string(name.ptr, name.size))
+            }
+            Obj::Instance(instance) => {
+                let name = unsafe { &(*(**instance).class).name };
+                write!(f, "{} instance", memory::read_string(name.ptr, name.size))
+            }
+            Obj::BoundMethod(bound_method) => {
+                write!(f, "{}", unsafe { &*(*(**bound_method).method).function })
+            }
+            Obj::Native(_) => write!(f, "<native fn>"),
+            Obj::List(list) => {
+                let items = print_once(*list as usize, || {
+                    let items: Vec<String> = unsafe { &(**list).items }
+                        .iter()
+                        .map(|item| item.to_string())
+                        .collect();
+                    items.join(", ")
+                });
+                write!(f, "[{}]", items.as_deref().unwrap_or("..."))
+            }
+            Obj::Map(map) => {
+                let entries = print_once(*map as usize, || {
+                    let entries: Vec<String> = unsafe { &(**map).entries }
+                        .iter()
+                        .map(|(key, value)| format!("{}: {}", key, value))
+                        .collect();
+                    entries.join(", ")
+                });
+                write!(f, "{{{}}}", entries.as_deref().unwrap_or("..."))
+            }
         }
-        false
     }
 }
 
@@ -249,35 +583,35 @@ impl From<FatPointer> for Obj {
 impl From<&mut str> for Obj {
     fn from(str_value: &mut str) -> Self {
         let hash_value = hasher::hash(str_value);
-        let str_ptr = memory::allocate::<String>();
+        let str_ptr = memory::allocate_bytes(str_value.len());
         memory::copy(str_value.as_mut_ptr(), str_ptr, str_value.len(), 0);
         let fat_ptr = FatPointer {
             ptr: str_ptr,
             size: str_value.len(),
             hash: hash_value,
         };
-        Obj::from(fat_ptr.clone())
+        Obj::from(fat_ptr)
     }
 }
 
-impl Into<FatPointer> for Obj {
-    fn into(self) -> FatPointer {
-        match self {
+impl From<Obj> for FatPointer {
+    fn from(obj: Obj) -> Self {
+        match obj {
             Obj::Str(ptr) => ptr,
             //@todo @pending check if it should be false this can be wrong in most cases
             // may be we should throw error
             _ => FatPointer {
-                ptr: "".to_string().as_mut_ptr(),
-                size: 0 as usize,
+                ptr: std::ptr::null_mut(),
+                size: 0,
                 hash: 0,
             },
         }
     }
 }
 
-impl Into<Function> for Obj {
-    fn into(self) -> Function {
-        match self {
+impl From<Obj> for *mut Function {
+    fn from(obj: Obj) -> Self {
+        match obj {
             Obj::Fun(function) => function,
             //@todo @pending check if it should be false this can be wrong in most cases
             // may be we should throw error
@@ -1,8 +1,8 @@
-use crate::chunk::Chunk;
-use crate::common::{FatPointer, Function, FunctionType, Obj, OpCode, Value};
-use crate::hash_map::Table;
-use crate::hasher;
-use crate::memory;
+use crate::chunk::{self, Chunk};
+use crate::common::{Function, FunctionType, Obj, OpCode, Value};
+use crate::debug;
+use crate::memory::{self, Heap, HeapObj};
+use crate::optimizer;
 use crate::scanner::{Scanner, Token, TokenType};
 use num_derive::FromPrimitive;
 
@@ -21,8 +21,10 @@ enum Precedence {
     Term = 7,
     Factor = 8,
     Unary = 9,
-    Call = 10,
-    Primary = 11,
+    // binds tighter than unary minus, -2 ** 2 is -4
+    Exponent = 10,
+    Call = 11,
+    Primary = 12,
 }
 
 const NOOP: Option<ParseFn> = None;
@@ -38,6 +40,12 @@ const VARIABLE: Option<ParseFn> = Some(|compiler, can_assign| compiler.variable(
 const OR: Option<ParseFn> = Some(|compiler, can_assign| compiler.or(can_assign));
 const AND: Option<ParseFn> = Some(|compiler, can_assign| compiler.and(can_assign));
 const CALL: Option<ParseFn> = Some(|compiler, can_assign| compiler.call(can_assign));
+const DOT: Option<ParseFn> = Some(|compiler, can_assign| compiler.dot(can_assign));
+const THIS: Option<ParseFn> = Some(|compiler, can_assign| compiler.this(can_assign));
+const SUPER: Option<ParseFn> = Some(|compiler, can_assign| compiler.super_(can_assign));
+const LIST: Option<ParseFn> = Some(|compiler, can_assign| compiler.list(can_assign));
+const MAP: Option<ParseFn> = Some(|compiler, can_assign| compiler.map(can_assign));
+const SUBSCRIPT: Option<ParseFn> = Some(|compiler, can_assign| compiler.subscript(can_assign));
 
 fn parse_rule(token_type: TokenType) -> ParseRule {
     match token_type {
@@ -46,6 +54,16 @@ fn parse_rule(token_type: TokenType) -> ParseRule {
             infix: CALL,
             precedence: Precedence::Call,
         },
+        TokenType::LeftBracket => ParseRule {
+            prefix: LIST,
+            infix: SUBSCRIPT,
+            precedence: Precedence::Call,
+        },
+        TokenType::LeftBrace => ParseRule {
+            prefix: MAP,
+            infix: NOOP,
+            precedence: Precedence::None,
+        },
         TokenType::Minus | TokenType::Bang => ParseRule {
             prefix: UNARY,
             infix: BINARY,
@@ -68,11 +86,16 @@ fn parse_rule(token_type: TokenType) -> ParseRule {
                 precedence: Precedence::Comparison,
             }
         }
-        TokenType::Star | TokenType::Slash => ParseRule {
+        TokenType::Star | TokenType::Slash | TokenType::Percent => ParseRule {
             prefix: NOOP,
             infix: BINARY,
             precedence: Precedence::Factor,
         },
+        TokenType::StarStar => ParseRule {
+            prefix: NOOP,
+            infix: BINARY,
+            precedence: Precedence::Exponent,
+        },
         TokenType::Number => ParseRule {
             prefix: NUMBER,
             infix: NOOP,
@@ -103,27 +126,22 @@ fn parse_rule(token_type: TokenType) -> ParseRule {
             infix: AND,
             precedence: Precedence::And,
         },
-        TokenType::Comma
-        | TokenType::Class
-        | TokenType::Else
-        | TokenType::For
-        | TokenType::Fun
-        | TokenType::If
-        | TokenType::Print
-        | TokenType::Return
-        | TokenType::Super
-        | TokenType::This
-        | TokenType::Var
-        | TokenType::While
-        | TokenType::Error
-        | TokenType::Eof
-        | TokenType::Semicolon
-        | TokenType::Equal
-        | TokenType::Dot
-        | TokenType::LeftBrace
-        | TokenType::RightBrace
-        | TokenType::RightParen
-        | _ => ParseRule {
+        TokenType::Dot => ParseRule {
+            prefix: NOOP,
+            infix: DOT,
+            precedence: Precedence::Call,
+        },
+        TokenType::This => ParseRule {
+            prefix: THIS,
+            infix: NOOP,
+            precedence: Precedence::None,
+        },
+        TokenType::Super => ParseRule {
+            prefix: SUPER,
+            infix: NOOP,
+            precedence: Precedence::None,
+        },
+        _ => ParseRule {
             prefix: NOOP,
             infix: NOOP,
             precedence: Precedence::None,
@@ -133,6 +151,19 @@ fn parse_rule(token_type: TokenType) -> ParseRule {
 
 type ParseFn = fn(compiler: &mut Compiler, can_assign: bool);
 
+// token for variables like `this` which the compiler declares
+// on its own, they have no name in the source.
+fn synthetic_token(token_type: TokenType, at: Token) -> Token {
+    Token {
+        token_type,
+        start: at.start,
+        length: 0,
+        line: at.line,
+        column: at.column,
+        message: None,
+    }
+}
+
 struct ParseRule {
     prefix: Option<ParseFn>,
     infix: Option<ParseFn>,
@@ -143,28 +174,43 @@ struct ParseRule {
 struct Parser {
     current: Option<Token>,
     previous: Option<Token>,
-    had_error: bool,
+    // every reported error, formatted for the user
+    errors: Vec<String>,
     panic_mode: bool,
 }
+// name of the local, scope depth it was declared in and whether any
+// closure captured it
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum Local {
-    Filled(Token, usize),
+    Filled(Token, usize, bool),
     Empty,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum UpValue {
-    Filled(u8, bool),
+    Filled(usize, bool),
     Empty,
 }
 
+#[derive(Debug, Clone)]
+struct LoopContext {
+    // where `continue` jumps back to
+    start: usize,
+    // locals deeper than this are declared in the body
+    scope_depth: usize,
+    // `break` jumps, patched once the end of the loop is known
+    breaks: Vec<usize>,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct CompilerContext {
-    function: Obj,
+    function: Function,
     locals: Vec<Local>,
     local_count: usize,
     up_values: Vec<UpValue>,
     up_value_count: usize,
+    // loops of this function we are currently in, innermost last
+    loops: Vec<LoopContext>,
 }
 
 impl CompilerContext {
@@ -180,53 +226,71 @@ impl CompilerContext {
             local_count: 1, // starting with 1 take first spot for top level function
             up_values,
             up_value_count: 0,
-            function: Obj::Fun(Function::new_function(FunctionType::Script)),
+            loops: vec![],
+            function: Function::new_function(FunctionType::Script),
         }
     }
 
     fn update_function_arity(&mut self, arity: u8) {
-        if let Obj::Fun(function) = &mut self.function {
-            function.arity = arity;
-        }
+        self.function.arity = arity;
+    }
+
+    fn function_type(&self) -> FunctionType {
+        self.function.func_type.clone()
+    }
+
+    fn update_function_up_value_count(&mut self) {
+        self.function.up_value_count = self.up_value_count;
     }
 }
 
+#[derive(Debug, Clone)]
+struct ClassContext {
+    has_superclass: bool,
+}
+
 pub(crate) struct Compiler<'c> {
-    table: &'c mut Table<Value>,
+    heap: &'c mut Heap,
     scanner: Scanner,
     parser: Parser,
     source: String,
     current_context: usize,
     scope_depth: usize,
     contexts: Vec<CompilerContext>,
+    // class declarations we are currently nested in, innermost last
+    classes: Vec<ClassContext>,
+    // prints the value of expression statements at the top level, for the repl
+    echo: bool,
 }
 
 impl<'c> Compiler<'c> {
-    pub(crate) fn init(scanner: Scanner, table: &'c mut Table<Value>) -> Compiler {
+    pub(crate) fn init(scanner: Scanner, heap: &'c mut Heap) -> Compiler<'c> {
         let parser = Parser {
             current: None,
             previous: None,
-            had_error: false,
+            errors: vec![],
             panic_mode: false,
         };
 
-        let mut contexts: Vec<CompilerContext> = vec![];
-        contexts.push(CompilerContext::init());
-
-        let compiler = Compiler {
+        Compiler {
             scanner,
             parser,
             source: "".to_string(),
-            table,
-            contexts,
+            heap,
+            contexts: vec![CompilerContext::init()],
             scope_depth: 0,
             current_context: 0,
-        };
+            classes: vec![],
+            echo: false,
+        }
+    }
 
-        compiler
+    pub(crate) fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
     }
 
-    pub(crate) fn compile(&mut self, source: String) -> (bool, Obj) {
+    // returns the function of the top level code or all reported errors.
+    pub(crate) fn compile(&mut self, source: String) -> Result<Function, Vec<String>> {
         self.source = source;
         let chars: Vec<char> = self.source.chars().collect();
         self.scanner.refresh(0, self.source.len(), chars);
@@ -235,30 +299,36 @@ impl<'c> Compiler<'c> {
             self.declaration();
         }
         self.end_compiler();
-        (
-            self.parser.had_error,
-            self.current_context().function.clone(),
-        )
+        if self.parser.errors.is_empty() {
+            Ok(self.current_context().function.clone())
+        } else {
+            Err(self.parser.errors.clone())
+        }
     }
 
     fn advance(&mut self) {
         self.parser.previous = self.parser.current;
         loop {
-            self.parser.current = Some(self.scanner.scan_token());
+            let token = self.scanner.scan_token();
+            self.parser.current = Some(token);
 
-            if self.parser.current.unwrap().token_type != TokenType::Error {
+            if token.token_type != TokenType::Error {
                 break;
             }
 
-            self.error_at_current("@todo some error here")
+            self.error_at_current(token.message.unwrap_or("Unexpected token."))
         }
     }
 
     fn declaration(&mut self) {
-        if self.match_token(TokenType::Fun) {
+        if self.match_token(TokenType::Class) {
+            self.class_decl();
+        } else if self.match_token(TokenType::Fun) {
             self.fun_decl();
         } else if self.match_token(TokenType::Var) {
             self.variable_decl();
+        } else if self.echo && self.is_top_level() && self.is_expression_statement() {
+            self.echo_statement();
         } else {
             self.statement();
         }
@@ -268,41 +338,99 @@ impl<'c> Compiler<'c> {
         }
     }
 
+    fn class_decl(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous_token();
+        let name_index = self.identifier();
+        self.declare_variable();
+        self.current_chunk()
+            .write_indexed(OpCode::Class, name_index, class_name.location());
+        self.define_variable(name_index);
+
+        self.classes.push(ClassContext {
+            has_superclass: false,
+        });
+
+        if self.match_token(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            let superclass_name = self.previous_token();
+            self.variable(false);
+            if self.token_name(superclass_name) == self.token_name(class_name) {
+                self.error("A class can't inherit from itself.");
+            }
+            // superclass is kept in a local named `super` so methods
+            // can capture it like any other variable.
+            self.begin_scope();
+            self.add_local(synthetic_token(TokenType::Super, superclass_name));
+            self.define_variable(0);
+
+            self.named_variable(class_name, false);
+            self.emit_opcode(OpCode::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // load class back on the stack so methods can be attached to it
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_opcode(OpCode::Pop);
+
+        if self.classes.last().unwrap().has_superclass {
+            self.end_scope();
+        }
+        self.classes.pop();
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let method_name = self.previous_token();
+        let name_index = self.identifier();
+        let function_type = if self.token_name(method_name) == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.current_chunk()
+            .write_indexed(OpCode::Method, name_index, method_name.location());
+    }
+
     fn fun_decl(&mut self) {
+        // local functions are declared before the body is compiled
+        // so they can refer to themselves recursively.
         let index = self.parse_variable();
-        let prev_token = self.parser.previous.unwrap();
-        self.function();
-        self.emit_opcode(OpCode::DefineGlobalVariable);
-        self.current_chunk().write_index(index, prev_token.line);
+        self.function(FunctionType::Closure);
+        self.define_variable(index);
     }
 
-    fn function(&mut self) {
+    fn function(&mut self, function_type: FunctionType) {
         let mut context = CompilerContext::init();
-        let mut function = Function::new_function(FunctionType::Closure);
-        let token = self.parser.previous.unwrap();
-        let str_value = &self.source[token.start..token.start + token.length];
-        let hash_value = hasher::hash(str_value);
-        let exiting_value = self.table.find_entry_with_value(str_value, hash_value);
-        function.name = exiting_value.cloned();
-        let function_obj = Obj::Fun(function);
-        context.function = function_obj;
+        if matches!(
+            function_type,
+            FunctionType::Method | FunctionType::Initializer
+        ) {
+            // slot zero holds the receiver for methods
+            let token = self.previous_token();
+            context.locals[0] = Local::Filled(synthetic_token(TokenType::This, token), 0, false);
+        }
+        let mut function = Function::new_function(function_type);
+        let str_value = self.prev_token_to_string();
+        function.name = Some(self.heap.intern(&str_value));
+        context.function = function;
         self.contexts.push(context);
         self.current_context += 1;
         self.begin_scope();
         self.consume(TokenType::LeftParen, "Expect '(' after function name");
-        let mut arity = 0;
-        //@todo parse arguments
+        let mut arity: usize = 0;
         if !self.check(TokenType::RightParen) {
             self.parse_and_define_parameter();
             arity += 1;
-            loop {
-                match self.match_token(TokenType::Comma) {
-                    true => {
-                        self.parse_and_define_parameter();
-                        arity += 1;
-                    }
-                    false => break,
-                }
+            while self.match_token(TokenType::Comma) {
+                self.parse_and_define_parameter();
+                arity += 1;
             }
         }
 
@@ -310,7 +438,7 @@ impl<'c> Compiler<'c> {
             self.error_at_current("Can't have more than 255 parameters.");
         }
 
-        self.current_context().update_function_arity(arity);
+        self.current_context().update_function_arity(arity as u8);
         self.consume(
             TokenType::RightParen,
             "Expect ')' at the end of function params",
@@ -321,35 +449,35 @@ impl<'c> Compiler<'c> {
         );
         self.block();
         self.end_scope();
+        self.contexts[self.current_context].update_function_up_value_count();
         self.end_compiler();
-        let inner_function = self.contexts[self.current_context + 1].function.clone();
-        let up_values = self.contexts[self.current_context + 1].up_values.clone();
-        self.contexts.remove(self.current_context + 1);
-        // reset old compiler state
-        let constant_index = self
-            .current_chunk()
-            .add_constant(Value::from(inner_function));
-        self.emit_opcode(OpCode::Closure);
-        self.emit_byte(constant_index as u8);
-        up_values.iter().for_each(|up_value| match up_value {
-            UpValue::Filled(index, true) => {
-                self.emit_byte(1);
-                self.emit_byte(*index);
-            }
-            UpValue::Filled(index, false) => {
-                self.emit_byte(0);
-                self.emit_byte(*index);
+        let inner = self.contexts.remove(self.current_context + 1);
+        let up_values = inner.up_values;
+        // nothing is collected while compiling, the constant keeps it alive once the vm runs
+        let inner_function = self.heap.allocate(inner.function, HeapObj::Function);
+        let constant_index = self.make_constant(Value::from(Obj::Fun(inner_function)));
+        // the long variant has long operands for the captures as well
+        let long = constant_index > u8::MAX as usize
+            || up_values.iter().any(|up_value| {
+                matches!(up_value, UpValue::Filled(index, _) if *index > u8::MAX as usize)
+            });
+        let location = self.previous_token().location();
+        let opcode = if long {
+            OpCode::ClosureLong
+        } else {
+            OpCode::Closure
+        };
+        self.emit_opcode(opcode);
+        self.current_chunk().write_operand(constant_index, long, location);
+        up_values.iter().for_each(|up_value| {
+            if let UpValue::Filled(index, is_local) = up_value {
+                self.emit_byte(*is_local as u8);
+                self.current_chunk().write_operand(*index, long, location);
             }
-            _ => (),
         });
     }
 
-    fn recursive_resolve_up_value(
-        &mut self,
-        name: Token,
-        context_index: usize,
-        scope_depth: usize,
-    ) -> i32 {
+    fn recursive_resolve_up_value(&mut self, name: Token, context_index: usize) -> i32 {
         /*
             let's say we have this:
             ```
@@ -370,36 +498,57 @@ impl<'c> Compiler<'c> {
             it will search in those locals but it doesn't exist so recursively it will call for index 3.
             then same logic will be applied and it will search x in index 2 which is our outer_1 function. x exists there
             so we will get a valid index. Then index 3 call will add a upvalue in its compiler context
-            and return index. which will be received by first call using context index 4 and it will also add
-            add local value using false.
+            and return the index of that upvalue. which will be received by first call using context index 4
+            and it will also add an upvalue pointing to upvalue of inner_1 using false.
         */
         if context_index == 0 {
             return -1;
         }
 
-        let context_opt = self.contexts.get(context_index - 1);
-        match context_opt {
-            Some(context) => {
-                let locals = context.locals.clone();
-                if let Some(index) = self.resolve_from_locals(locals, scope_depth - 1, name) {
-                    self.add_up_value(index as u8, true, context_index);
-                    return index;
-                } else {
-                    let r_index =
-                        self.recursive_resolve_up_value(name, context_index - 1, scope_depth - 1);
-                    self.add_up_value(r_index as u8, false, context_index);
-                    r_index
-                }
-            }
-            None => -1,
+        if let Some(index) = self.resolve_from_locals(context_index - 1, name) {
+            self.mark_captured(context_index - 1, index as usize);
+            return self.add_up_value(index as usize, true, context_index);
+        }
+
+        let up_value_index = self.recursive_resolve_up_value(name, context_index - 1);
+        if up_value_index != -1 {
+            return self.add_up_value(up_value_index as usize, false, context_index);
+        }
+        -1
+    }
+
+    fn mark_captured(&mut self, context_index: usize, local_index: usize) {
+        if let Local::Filled(token, depth, _) = self.contexts[context_index].locals[local_index] {
+            self.contexts[context_index].locals[local_index] = Local::Filled(token, depth, true);
         }
     }
 
-    fn add_up_value(&mut self, index: u8, is_local: bool, context_index: usize) {
-        let up_value = UpValue::Filled(index, is_local);
+    fn add_up_value(&mut self, index: usize, is_local: bool, context_index: usize) -> i32 {
         let up_value_count = self.contexts[context_index].up_value_count;
-        self.contexts[context_index].up_values[up_value_count] = up_value;
+        // a closure can reference the same variable multiple times
+        for (i, up_value) in self.contexts[context_index].up_values[0..up_value_count]
+            .iter()
+            .enumerate()
+        {
+            if let UpValue::Filled(existing_index, existing_is_local) = up_value {
+                if *existing_index == index && *existing_is_local == is_local {
+                    return i as i32;
+                }
+            }
+        }
+
+        if up_value_count > chunk::MAX_INDEX {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        let up_values = &mut self.contexts[context_index].up_values;
+        if up_value_count == up_values.len() {
+            up_values.push(UpValue::Empty);
+        }
+        up_values[up_value_count] = UpValue::Filled(index, is_local);
         self.contexts[context_index].up_value_count += 1;
+        up_value_count as i32
     }
 
     fn parse_and_define_parameter(&mut self) {
@@ -423,7 +572,7 @@ impl<'c> Compiler<'c> {
         self.consume(TokenType::Identifier, "Expected name after variable");
         self.declare_variable();
         let mut index = 0;
-        if self.scope_depth <= 0 {
+        if self.scope_depth == 0 {
             index = self.identifier();
         }
         index
@@ -431,99 +580,205 @@ impl<'c> Compiler<'c> {
 
     fn declare_variable(&mut self) {
         if self.scope_depth > 0 {
-            if self.current_context().local_count == 255 {
+            if self.current_context().local_count > chunk::MAX_INDEX {
                 self.error("Too many local variables in function.");
                 return;
             }
             let token = self.parser.previous.unwrap();
-            let local = Local::Filled(token, self.scope_depth);
-
-            let matching_token = self.resolve_local(token);
-
-            if matching_token != -1 {
+            if self.is_declared_in_scope(token) {
                 self.error("Already a variable with this name in this scope.");
             }
-            let local_count = self.current_context().local_count;
-            self.current_context().locals[local_count] = local;
-            self.current_context().local_count += 1;
+            self.add_local(token);
         }
     }
 
-    fn resolve_local(&mut self, token: Token) -> i32 {
-        if self.current_context().local_count <= 0 {
-            return -1;
+    fn add_local(&mut self, token: Token) {
+        let local = Local::Filled(token, self.scope_depth, false);
+        let local_count = self.current_context().local_count;
+        let locals = &mut self.current_context().locals;
+        if local_count == locals.len() {
+            locals.push(Local::Empty);
         }
-        let scope_depth = self.scope_depth;
-        let locals = self.current_context().locals.clone();
+        locals[local_count] = local;
+        self.current_context().local_count += 1;
+    }
 
-        if let Some(value) = self.resolve_from_locals(locals, scope_depth, token) {
+    fn is_declared_in_scope(&self, token: Token) -> bool {
+        let context = &self.contexts[self.current_context];
+        for existing in context.locals[0..context.local_count].iter().rev() {
+            if let Local::Filled(existing_token, depth, _) = existing {
+                if *depth < self.scope_depth {
+                    break;
+                }
+                if self.identifiers_equal(*existing_token, token) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn resolve_local(&mut self, token: Token) -> i32 {
+        if let Some(value) = self.resolve_from_locals(self.current_context, token) {
             return value;
         }
         -1
     }
 
-    fn resolve_from_locals(
-        &mut self,
-        locals: Vec<Local>,
-        scope_depth: usize,
-        token: Token,
-    ) -> Option<i32> {
-        for (idx, existing) in locals.iter().enumerate().rev() {
-            match existing {
-                Local::Filled(existing_token, depth) => {
-                    if depth.ge(&scope_depth) {
-                        if token.length != existing_token.length {
-                            continue;
-                        }
-                        let existing_token_deref = existing_token.clone();
-                        let existing_name = self.token_name(existing_token_deref);
-                        let local_name = self.token_name(token);
-                        if local_name == existing_name {
-                            return Some(idx as i32);
-                        }
-                    }
+    // only locals which are still in scope (below local_count) can be resolved,
+    // we search backwards so inner declarations shadow outer ones.
+    fn resolve_from_locals(&self, context_index: usize, token: Token) -> Option<i32> {
+        let context = &self.contexts[context_index];
+        for (idx, existing) in context.locals[0..context.local_count]
+            .iter()
+            .enumerate()
+            .rev()
+        {
+            if let Local::Filled(existing_token, _, _) = existing {
+                if self.identifiers_equal(*existing_token, token) {
+                    return Some(idx as i32);
                 }
-                _ => continue,
             }
         }
         None
     }
 
+    // `this` and `super` are not part of the source so they are matched by token type.
+    fn identifiers_equal(&self, existing: Token, token: Token) -> bool {
+        let is_synthetic = |token: Token| {
+            matches!(token.token_type, TokenType::This | TokenType::Super)
+        };
+        if is_synthetic(existing) || is_synthetic(token) {
+            return existing.token_type == token.token_type;
+        }
+        existing.length == token.length && self.token_name(existing) == self.token_name(token)
+    }
+
     fn variable(&mut self, can_assign: bool) {
         let token = self.parser.previous.unwrap();
+        self.named_variable(token, can_assign);
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        self.variable(false);
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        let super_token = self.previous_token();
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            _ => (),
+        }
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name_index = self.identifier();
+
+        // method is looked up on the superclass but bound to this instance
+        self.named_variable(synthetic_token(TokenType::This, super_token), false);
+        self.named_variable(synthetic_token(TokenType::Super, super_token), false);
+        self.current_chunk()
+            .write_indexed(OpCode::GetSuper, name_index, super_token.location());
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let property = self.previous_token();
+        let name_index = self.identifier();
+        let opcode = if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            OpCode::SetProperty
+        } else {
+            OpCode::GetProperty
+        };
+        self.current_chunk()
+            .write_indexed(opcode, name_index, property.location());
+    }
+
+    // [a, b, c], a trailing comma is allowed
+    fn list(&mut self, _can_assign: bool) {
+        let bracket = self.previous_token();
+        let mut count = 0;
+        while !self.check(TokenType::RightBracket) {
+            self.expression();
+            count += 1;
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+        if count > chunk::MAX_INDEX {
+            self.error("Too many items in a list.");
+        }
+        self.current_chunk()
+            .write_indexed(OpCode::BuildList, count, bracket.location());
+    }
+
+    // {key: value}, a trailing comma is allowed. Statements starting with a
+    // brace are blocks so this is only reached inside expressions.
+    fn map(&mut self, _can_assign: bool) {
+        let brace = self.previous_token();
+        let mut count = 0;
+        while !self.check(TokenType::RightBrace) {
+            self.expression();
+            self.consume(TokenType::Colon, "Expect ':' after map key.");
+            self.expression();
+            count += 1;
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+        if count > chunk::MAX_INDEX {
+            self.error("Too many entries in a map.");
+        }
+        self.current_chunk()
+            .write_indexed(OpCode::BuildMap, count, brace.location());
+    }
+
+    fn subscript(&mut self, can_assign: bool) {
+        let bracket = self.previous_token();
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_opcode_at(OpCode::SetIndex, bracket);
+        } else {
+            self.emit_opcode_at(OpCode::GetIndex, bracket);
+        }
+    }
+
+    fn named_variable(&mut self, token: Token, can_assign: bool) {
         let mut existing_index = self.resolve_local(token);
-        let mut set_op = OpCode::Nil;
-        let mut get_op = OpCode::Nil;
-        if existing_index >= 0 {
-            set_op = OpCode::SetLocalVariable;
-            get_op = OpCode::GetLocalVariable;
+        let (set_op, get_op) = if existing_index >= 0 {
+            (OpCode::SetLocalVariable, OpCode::GetLocalVariable)
         } else {
-            existing_index =
-                self.recursive_resolve_up_value(token, self.current_context, self.scope_depth);
+            existing_index = self.recursive_resolve_up_value(token, self.current_context);
             if existing_index != -1 {
-                set_op = OpCode::SetUpValue;
-                get_op = OpCode::GetUpValue;
+                (OpCode::SetUpValue, OpCode::GetUpValue)
             } else {
-                let index = self.identifier();
-                existing_index = index as i32;
-                set_op = OpCode::SetGlobalVariable;
-                get_op = OpCode::GetGlobalVariable;
+                existing_index = self.identifier_constant(token) as i32;
+                (OpCode::SetGlobalVariable, OpCode::GetGlobalVariable)
             }
-        }
+        };
         let prev_token = self.previous_token();
         if can_assign && self.match_token(TokenType::Equal) {
             self.expression();
-            self.emit_opcode(set_op);
             self.current_chunk()
                 // @type_conversion this conversion here to usize will result in usize::MAX
                 // when existing_index is -1
-                .write_index(existing_index as usize, prev_token.line);
+                .write_indexed(set_op, existing_index as usize, prev_token.location());
         } else {
-            self.emit_opcode(get_op);
             self.current_chunk()
                 // @type_conversion this conversion here to usize will result in usize::MAX
                 // when existing_index is -1
-                .write_index(existing_index as usize, prev_token.line);
+                .write_indexed(get_op, existing_index as usize, prev_token.location());
         }
     }
 
@@ -532,14 +787,20 @@ impl<'c> Compiler<'c> {
             return;
         }
         let prev_token = self.previous_token();
-        self.emit_opcode(OpCode::DefineGlobalVariable);
-        self.current_chunk().write_index(index, prev_token.line);
+        self.current_chunk()
+            .write_indexed(OpCode::DefineGlobalVariable, index, prev_token.location());
     }
 
     fn identifier(&mut self) -> usize {
         self.string(false, false)
     }
 
+    fn identifier_constant(&mut self, token: Token) -> usize {
+        let str_value = self.token_to_string(token);
+        let fat_ptr = self.heap.intern(&str_value);
+        self.make_constant(Value::from(Obj::from(fat_ptr)))
+    }
+
     fn statement(&mut self) {
         if self.match_token(TokenType::Print) {
             self.print_stmt();
@@ -551,6 +812,10 @@ impl<'c> Compiler<'c> {
             self.while_stmt();
         } else if self.match_token(TokenType::For) {
             self.for_stmt();
+        } else if self.match_token(TokenType::Break) {
+            self.break_stmt();
+        } else if self.match_token(TokenType::Continue) {
+            self.continue_stmt();
         } else if self.match_token(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -561,9 +826,15 @@ impl<'c> Compiler<'c> {
     }
 
     fn return_stmt(&mut self) {
+        if self.current_context == 0 {
+            self.error("Can't return from top-level code.");
+        }
         if self.match_token(TokenType::Semicolon) {
             self.emit_return();
         } else {
+            if matches!(self.current_context().function_type(), FunctionType::Initializer) {
+                self.error("Can't return a value from an initializer.");
+            }
             self.expression();
             self.consume_semicolon();
             self.emit_opcode(OpCode::Return);
@@ -610,6 +881,7 @@ impl<'c> Compiler<'c> {
             self.patch_jump(body_jump);
         }
 
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
         // jump to end of loop if condition is false but
@@ -618,6 +890,7 @@ impl<'c> Compiler<'c> {
             self.patch_jump(end_loop);
             self.emit_opcode(OpCode::Pop) // pop false
         }
+        self.end_loop();
 
         self.end_scope();
     }
@@ -632,21 +905,91 @@ impl<'c> Compiler<'c> {
         );
         let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
         self.emit_opcode(OpCode::Pop); // remove truthy result
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
         self.patch_jump(exit_jump);
         self.emit_opcode(OpCode::Pop); // remove falsey result
+        self.end_loop();
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        let scope_depth = self.scope_depth;
+        self.current_context().loops.push(LoopContext {
+            start,
+            scope_depth,
+            breaks: vec![],
+        });
+    }
+
+    // breaks land after the condition is popped, it was already popped
+    // when the body started
+    fn end_loop(&mut self) {
+        let current_loop = self.current_context().loops.pop().unwrap();
+        for jump in current_loop.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn break_stmt(&mut self) {
+        let scope_depth = match self.current_context().loops.last() {
+            Some(current_loop) => current_loop.scope_depth,
+            None => {
+                self.error("Can't use 'break' outside of a loop.");
+                return self.consume_semicolon();
+            }
+        };
+        self.consume_semicolon();
+        self.discard_locals(scope_depth);
+        let jump = self.emit_jump(OpCode::Jump);
+        self.current_context().loops.last_mut().unwrap().breaks.push(jump);
     }
 
+    fn continue_stmt(&mut self) {
+        let (start, scope_depth) = match self.current_context().loops.last() {
+            Some(current_loop) => (current_loop.start, current_loop.scope_depth),
+            None => {
+                self.error("Can't use 'continue' outside of a loop.");
+                return self.consume_semicolon();
+            }
+        };
+        self.consume_semicolon();
+        self.discard_locals(scope_depth);
+        self.emit_loop(start);
+    }
+
+    // pops locals deeper than `depth` off the stack when jumping out of
+    // their scope, the compiler keeps them as the code after the jump is
+    // still in that scope
+    fn discard_locals(&mut self, depth: usize) {
+        let context = self.current_context();
+        let opcodes: Vec<OpCode> = context.locals[..context.local_count]
+            .iter()
+            .rev()
+            .map_while(|local| match local {
+                Local::Filled(_, local_depth, true) if *local_depth > depth => {
+                    Some(OpCode::CloseUpValue)
+                }
+                Local::Filled(_, local_depth, false) if *local_depth > depth => Some(OpCode::Pop),
+                _ => None,
+            })
+            .collect();
+        for opcode in opcodes {
+            self.emit_opcode(opcode);
+        }
+    }
+
+    // loops are emitted long like jumps, the optimizer shortens them
     fn emit_loop(&mut self, loop_start: usize) {
-        self.emit_opcode(OpCode::Loop);
-        let jump = (self.current_chunk().code.len() - loop_start + 2) as u16;
+        self.emit_opcode(OpCode::LoopLong);
+        let jump = self.current_chunk().code.len() - loop_start + 4;
 
-        if jump > u16::MAX {
-            self.error(format!("Can not jump more than {:?} bytes", u16::MAX).as_str());
+        if jump > u32::MAX as usize {
+            self.error(format!("Can not jump more than {:?} bytes", u32::MAX).as_str());
         } else {
-            self.emit_byte(((jump >> 8) & 0xff) as u8);
-            self.emit_byte((jump & 0xff) as u8);
+            for shift in [24, 16, 8, 0] {
+                self.emit_byte(((jump >> shift) & 0xff) as u8);
+            }
         }
     }
 
@@ -670,35 +1013,36 @@ impl<'c> Compiler<'c> {
     }
 
     fn emit_jump(&mut self, instruction: OpCode) -> usize {
-        self.emit_opcode(instruction);
-        //We use two bytes for the jump offset operand.
-        //A 16-bit offset lets us jump over up to 65,535 bytes of code,
-        // which should be plenty for our needs.
-        self.emit_byte(0xff);
-        self.emit_byte(0xff);
-        // return index to where we emit two bytes for offset operand.
-        self.current_chunk().code.len() - 2
+        // How far the jump goes isn't known yet so it gets four bytes for
+        // the offset, once the function is done the optimizer turns every
+        // jump that fits into the two byte version.
+        self.emit_opcode(instruction.long_variant());
+        for _ in 0..4 {
+            self.emit_byte(0xff);
+        }
+        // return index to where we emit four bytes for offset operand.
+        self.current_chunk().code.len() - 4
     }
 
     fn patch_jump(&mut self, offset: usize) {
         // if we start emitting jump_if_else when ip is set to 5
-        // jump_if_else will go at 6, first 8 bits of offset to 7 and last 8 bits
-        // to 8th index of ip. Offset returned from emit_jump will be 6
-        // as it removes the offset bytes. Lets assume we push 4 instructions as part of
-        // if block. We calculate how much to jump if if condition is false.
-        // 12 - 6 - 2 = 4, we need to skip 4 bytes which makes sense because
+        // jump_if_else will go at 6, the four bytes of the offset to 7..=10.
+        // Offset returned from emit_jump will be 6 as it removes the offset
+        // bytes. Lets assume we push 4 instructions as part of if block.
+        // We calculate how much to jump if if condition is false.
+        // 14 - 6 - 4 = 4, we need to skip 4 bytes which makes sense because
         // we did insert 4 instructions as part of if block.
-        // -2 to adjust for the bytecode for the jump offset itself.
-        let jump = (self.current_chunk().code.len() - offset - 2) as u16;
+        // -4 to adjust for the bytecode for the jump offset itself.
+        let jump = self.current_chunk().code.len() - offset - 4;
 
-        if jump > u16::MAX {
-            self.error(format!("Can not jump more than {:?} bytes", u16::MAX).as_str());
+        if jump > u32::MAX as usize {
+            self.error(format!("Can not jump more than {:?} bytes", u32::MAX).as_str());
         } else {
-            // get msb 8 bits from the offset and mask with 0xff to
-            // make other bits are reset
-            self.current_chunk().code[offset] = ((jump >> 8) & 0xff) as u8;
-            // get lsb 8 bits
-            self.current_chunk().code[offset + 1] = (jump & 0xff) as u8;
+            // most significant 8 bits first, each masked with 0xff to
+            // make sure other bits are reset
+            for (i, shift) in [24, 16, 8, 0].into_iter().enumerate() {
+                self.current_chunk().code[offset + i] = ((jump >> shift) & 0xff) as u8;
+            }
         }
     }
 
@@ -716,23 +1060,25 @@ impl<'c> Compiler<'c> {
 
     fn end_scope(&mut self) {
         self.scope_depth -= 1;
-        let scoped_locals = self
-            .current_context()
-            .locals
-            .clone()
-            .iter()
-            .filter(|local| match local {
-                Local::Filled(_, depth) => depth.gt(&self.scope_depth),
-                _ => false,
-            })
-            .count();
-        if self.current_context().local_count == 0 {
-            return;
-        }
-        for _ in 1..=scoped_locals {
-            self.emit_opcode(OpCode::Pop);
+        // slot zero is reserved so there is always a local below the scoped ones.
+        loop {
+            let local_count = self.current_context().local_count;
+            let (depth, is_captured) = match self.current_context().locals[local_count - 1] {
+                Local::Filled(_, depth, is_captured) => (depth, is_captured),
+                Local::Empty => break,
+            };
+            if depth <= self.scope_depth {
+                break;
+            }
+            // captured locals are moved to the heap so closures can outlive the scope
+            if is_captured {
+                self.emit_opcode(OpCode::CloseUpValue);
+            } else {
+                self.emit_opcode(OpCode::Pop);
+            }
+            self.current_context().locals[local_count - 1] = Local::Empty;
+            self.current_context().local_count -= 1;
         }
-        self.current_context().local_count -= scoped_locals;
     }
 
     fn expression_statement(&mut self) {
@@ -741,6 +1087,35 @@ impl<'c> Compiler<'c> {
         self.emit_opcode(OpCode::Pop);
     }
 
+    // like an expression statement but the value is printed instead of
+    // dropped, the last one of an entry doesn't need a semicolon.
+    fn echo_statement(&mut self) {
+        self.expression();
+        if !self.check(TokenType::Eof) {
+            self.consume_semicolon();
+        }
+        self.emit_opcode(OpCode::Print);
+    }
+
+    fn is_top_level(&self) -> bool {
+        self.current_context == 0 && self.scope_depth == 0
+    }
+
+    // whether `statement` would compile the next tokens as an expression statement.
+    fn is_expression_statement(&self) -> bool {
+        !matches!(
+            self.parser.current.map(|token| token.token_type),
+            Some(TokenType::Print)
+                | Some(TokenType::If)
+                | Some(TokenType::Return)
+                | Some(TokenType::While)
+                | Some(TokenType::For)
+                | Some(TokenType::Break)
+                | Some(TokenType::Continue)
+                | Some(TokenType::LeftBrace)
+        )
+    }
+
     fn consume_semicolon(&mut self) {
         self.consume(
             TokenType::Semicolon,
@@ -752,7 +1127,7 @@ impl<'c> Compiler<'c> {
         self.parser.panic_mode = false;
 
         while !self.check(TokenType::Eof) {
-            if self.check(TokenType::Semicolon) {
+            if self.previous_token().token_type == TokenType::Semicolon {
                 return;
             }
 
@@ -764,6 +1139,8 @@ impl<'c> Compiler<'c> {
                 | TokenType::Var
                 | TokenType::Print
                 | TokenType::For
+                | TokenType::Break
+                | TokenType::Continue
                 | TokenType::Return => return,
                 _ => self.advance(),
             }
@@ -805,16 +1182,15 @@ impl<'c> Compiler<'c> {
             return;
         }
         self.parser.panic_mode = true;
-        eprint!("[line: {}] Error", token.line);
-
-        match token.token_type {
-            TokenType::Eof => eprint!(" at end"),
-            TokenType::Error => eprint!(""),
-            _ => eprint!(" at {}.{}", token.length, token.start),
-        }
-
-        eprintln!(": {}", message);
-        self.parser.had_error = true;
+        let location = match token.token_type {
+            TokenType::Eof => " at end".to_string(),
+            TokenType::Error => "".to_string(),
+            _ => format!(" at '{}'", self.token_name(token)),
+        };
+        self.parser.errors.push(format!(
+            "[line {}, column {}] Error{}: {}",
+            token.line, token.column, location, message
+        ));
     }
 
     fn consume(&mut self, token_type: TokenType, message: &str) {
@@ -828,25 +1204,33 @@ impl<'c> Compiler<'c> {
 
     fn emit_byte(&mut self, byte: u8) {
         let prev_token = self.previous_token();
-        self.current_chunk().write_chunk(byte, prev_token.line);
-    }
-
-    fn emit_bytes(&mut self, byte_1: u8, byte_2: u8) {
-        self.emit_byte(byte_1);
-        self.emit_byte(byte_2);
+        self.current_chunk().write_chunk(byte, prev_token.location());
     }
 
     fn emit_opcode(&mut self, opcode: OpCode) {
         self.emit_byte(opcode as u8);
     }
 
-    fn emit_opcodes(&mut self, opcode_1: OpCode, opcode_2: OpCode) {
-        self.emit_opcode(opcode_1);
-        self.emit_opcode(opcode_2);
+    // for code that belongs to a token before the last one parsed, like the
+    // operator of a binary expression, so errors point at the operator
+    fn emit_opcode_at(&mut self, opcode: OpCode, token: Token) {
+        self.current_chunk().write_chunk(opcode as u8, token.location());
     }
 
     fn end_compiler(&mut self) {
         self.emit_return();
+        // the code may be broken after an error, it's thrown away anyway
+        if self.parser.errors.is_empty() {
+            let chunk = &mut self.contexts[self.current_context].function.chunk;
+            optimizer::optimize(chunk, self.heap);
+        }
+        if debug::options().code {
+            let name = match &self.current_context().function.name {
+                Some(name) => memory::read_string(name.ptr, name.size),
+                None => "<script>".to_string(),
+            };
+            self.current_chunk().disassemble_chunk(&name);
+        }
         // do it only for inner functions
         if self.current_context > 0 {
             self.current_context -= 1;
@@ -854,7 +1238,14 @@ impl<'c> Compiler<'c> {
     }
 
     fn emit_return(&mut self) {
-        self.emit_opcode(OpCode::Nil);
+        // initializers always return the instance
+        if matches!(self.current_context().function_type(), FunctionType::Initializer) {
+            let location = self.previous_token().location();
+            self.current_chunk()
+                .write_indexed(OpCode::GetLocalVariable, 0, location);
+        } else {
+            self.emit_opcode(OpCode::Nil);
+        }
         self.emit_opcode(OpCode::Return);
     }
 
@@ -864,7 +1255,20 @@ impl<'c> Compiler<'c> {
 
     fn emit_constant(&mut self, value: Value) -> usize {
         let prev_token = self.previous_token();
-        self.current_chunk().write_constant(value, prev_token.line)
+        let index = self.make_constant(value);
+        self.current_chunk()
+            .write_indexed(OpCode::Constant, index, prev_token.location());
+        index
+    }
+
+    fn make_constant(&mut self, value: Value) -> usize {
+        match self.current_chunk().add_constant(value) {
+            Some(index) => index,
+            None => {
+                self.error("Too many constants in one chunk.");
+                0
+            }
+        }
     }
 
     fn str_to_float(&mut self, token: Token) -> f64 {
@@ -894,84 +1298,47 @@ impl<'c> Compiler<'c> {
     }
 
     fn call(&mut self, _can_assign: bool) {
+        let paren = self.previous_token();
         let mut arg_count = 0;
         if !self.check(TokenType::RightParen) {
             self.expression();
             arg_count += 1;
-            loop {
-                match self.match_token(TokenType::Comma) {
-                    true => {
-                        self.expression();
-                        arg_count += 1;
-                        if arg_count == 255 {
-                            self.error("Can't have more than 255 arguments");
-                        }
-                    }
-                    false => break,
+            while self.match_token(TokenType::Comma) {
+                self.expression();
+                if arg_count == 255 {
+                    self.error("Can't have more than 255 arguments");
+                } else {
+                    arg_count += 1;
                 }
             }
         }
         self.consume(TokenType::RightParen, "Expected ')' in function call.");
-        self.emit_opcode(OpCode::Call);
-        self.emit_byte(arg_count);
+        self.emit_opcode_at(OpCode::Call, paren);
+        self.current_chunk().write_chunk(arg_count, paren.location());
     }
 
     fn string(&mut self, _can_assign: bool, emit_constant: bool) -> usize {
-        let (str_value, hash_value) = self.prev_token_to_string();
-        let exiting_value = self.get_existing_string(&str_value, hash_value);
-        match exiting_value {
-            Some(existing) => {
-                let existing_ptr = existing.to_owned();
-                self.reuse_existing_string(existing_ptr, emit_constant)
-            }
-            None => self.create_new_string(str_value, hash_value, emit_constant),
-        }
-    }
-
-    fn reuse_existing_string(&mut self, existing: FatPointer, emit_constant: bool) -> usize {
-        let obj_string = Obj::from(existing);
-        let value = Value::from(obj_string);
+        let str_value = self.prev_token_to_string();
+        let fat_ptr = self.heap.intern(&str_value);
+        let value = Value::from(Obj::from(fat_ptr));
         if emit_constant {
             self.emit_constant(value)
         } else {
-            self.current_chunk().add_constant(value)
-        }
-    }
-
-    fn create_new_string(
-        &mut self,
-        mut str_value: String,
-        hash_value: u32,
-        emit_constant: bool,
-    ) -> usize {
-        let str_ptr = memory::allocate::<String>();
-        let src = str_value.as_mut_ptr();
-        memory::copy(src, str_ptr, str_value.len(), 0);
-        let fat_ptr = FatPointer {
-            ptr: str_ptr,
-            size: str_value.len(),
-            hash: hash_value,
-        };
-        let obj_string = Obj::from(fat_ptr.clone());
-        let value = Value::from(obj_string);
-        self.table.insert(fat_ptr.clone(), Value::Missing);
-        if emit_constant {
-            self.emit_constant(value)
-        } else {
-            self.current_chunk().add_constant(value)
+            self.make_constant(value)
         }
     }
 
-    fn get_existing_string(&mut self, str_value: &str, hash_value: u32) -> Option<&FatPointer> {
-        let exiting_value = self.table.find_entry_with_value(str_value, hash_value);
-        exiting_value
+    fn prev_token_to_string(&mut self) -> String {
+        self.token_to_string(self.parser.previous.unwrap())
     }
 
-    fn prev_token_to_string(&mut self) -> (String, u32) {
-        let token = self.parser.previous.unwrap();
-        let str_value = self.token_name(token).to_owned();
-        let hash_value = hasher::hash(&str_value);
-        (str_value, hash_value)
+    fn token_to_string(&mut self, token: Token) -> String {
+        let mut str_value = self.token_name(token);
+        // string literal tokens include the surrounding quotes
+        if token.token_type == TokenType::String {
+            str_value = &str_value[1..str_value.len() - 1];
+        }
+        str_value.to_owned()
     }
 
     fn grouping(&mut self, _can_assign: bool) {
@@ -980,34 +1347,37 @@ impl<'c> Compiler<'c> {
     }
 
     fn unary(&mut self, _can_assign: bool) {
-        let operator_type = self.parser.previous.unwrap().token_type;
+        let operator = self.previous_token();
 
         // we put expression first because we would first evaluate the operand
         // then put in on stack then pop it and negate.
         self.parse_precedence(Precedence::Unary);
 
-        match operator_type {
-            TokenType::Minus => self.emit_opcode(OpCode::Negate),
-            TokenType::Bang => self.emit_opcode(OpCode::Not),
-            _ => return,
+        match operator.token_type {
+            TokenType::Minus => self.emit_opcode_at(OpCode::Negate, operator),
+            TokenType::Bang => self.emit_opcode_at(OpCode::Not, operator),
+            _ => (),
         }
     }
 
-    fn emit_operator(&mut self, operator_type: TokenType) {
-        match operator_type {
-            TokenType::Minus => self.emit_opcode(OpCode::Subtract),
-            TokenType::Plus => self.emit_opcode(OpCode::Add),
-            TokenType::Star => self.emit_opcode(OpCode::Multiply),
-            TokenType::Slash => self.emit_opcode(OpCode::Divide),
-            TokenType::Greater => self.emit_opcode(OpCode::Greater),
-            TokenType::GreaterEqual => self.emit_opcodes(OpCode::Less, OpCode::Not),
-            TokenType::Less => self.emit_opcode(OpCode::Less),
-            TokenType::LessEqual => self.emit_opcodes(OpCode::Greater, OpCode::Not),
-            TokenType::EqualEqual => self.emit_opcode(OpCode::Equal),
-            TokenType::BangEqual => self.emit_opcodes(OpCode::Equal, OpCode::Not),
+    fn emit_operator(&mut self, operator: Token) {
+        let opcode = match operator.token_type {
+            TokenType::Minus => OpCode::Subtract,
+            TokenType::Plus => OpCode::Add,
+            TokenType::Star => OpCode::Multiply,
+            TokenType::Slash => OpCode::Divide,
+            TokenType::Percent => OpCode::Modulo,
+            TokenType::StarStar => OpCode::Power,
+            TokenType::Greater => OpCode::Greater,
+            TokenType::GreaterEqual => OpCode::GreaterEqual,
+            TokenType::Less => OpCode::Less,
+            TokenType::LessEqual => OpCode::LessEqual,
+            TokenType::EqualEqual => OpCode::Equal,
+            TokenType::BangEqual => OpCode::NotEqual,
 
             _ => return,
-        }
+        };
+        self.emit_opcode_at(opcode, operator);
     }
 
     fn get_rule(&mut self, token_type: TokenType) -> ParseRule {
@@ -1015,11 +1385,16 @@ impl<'c> Compiler<'c> {
     }
 
     fn binary(&mut self, _can_assign: bool) {
-        let operator_type = self.parser.previous.unwrap().token_type;
-        let rule = self.get_rule(operator_type);
-        let next_op: Precedence = num::FromPrimitive::from_u8((rule.precedence) as u8 + 1).unwrap();
+        let operator = self.previous_token();
+        let rule = self.get_rule(operator.token_type);
+        let next_op: Precedence = if operator.token_type == TokenType::StarStar {
+            // right associative, and the exponent can be negated like in 2 ** -1
+            Precedence::Unary
+        } else {
+            num::FromPrimitive::from_u8((rule.precedence) as u8 + 1).unwrap()
+        };
         self.parse_precedence(next_op);
-        self.emit_operator(operator_type);
+        self.emit_operator(operator);
     }
 
     fn literal(&mut self, _can_assign: bool) {
@@ -1028,7 +1403,7 @@ impl<'c> Compiler<'c> {
             TokenType::False => self.emit_opcode(OpCode::False),
             TokenType::Nil => self.emit_opcode(OpCode::Nil),
             TokenType::True => self.emit_opcode(OpCode::True),
-            _ => println!("Unknown type: {:?} ", token_type),
+            _ => self.error("Expect a literal."),
         }
     }
 
@@ -1070,7 +1445,7 @@ impl<'c> Compiler<'c> {
     }
 
     fn current_chunk(&mut self) -> &mut Chunk {
-        self.current_context().function.get_func_chunk()
+        &mut self.current_context().function.chunk
     }
 
     fn current_context(&mut self) -> &mut CompilerContext {
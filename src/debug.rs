@@ -1,33 +1,52 @@
-use crate::common::{Obj, Value};
-use crate::memory;
+use std::cell::RefCell;
+use std::io::{self, Write};
 
-static INFO: bool = true;
-static DEBUG: bool = true;
-pub(crate) static PRINT_STACK: bool = false;
+// What gets traced, everything is off by default so only the program
+// itself writes to stdout.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TraceOptions {
+    // every executed instruction and what the vm does for it
+    pub(crate) exec: bool,
+    // contents of the value stack before each instruction
+    pub(crate) stack: bool,
+    // disassembly of every function once it's compiled
+    pub(crate) code: bool,
+}
+
+struct Tracer {
+    options: TraceOptions,
+    sink: Box<dyn Write>,
+}
+
+thread_local! {
+    static TRACER: RefCell<Tracer> = RefCell::new(Tracer {
+        options: TraceOptions::default(),
+        sink: Box::new(io::stderr()),
+    });
+}
+
+pub(crate) fn configure(options: TraceOptions, sink: Box<dyn Write>) {
+    TRACER.with(|tracer| *tracer.borrow_mut() = Tracer { options, sink });
+}
 
-pub fn debug(message: String, new_line: bool) {
-    if DEBUG && new_line {
-        println!("[DEBUG] {}", message)
-    } else if DEBUG && !new_line {
-        print!("{}", message)
-    }
+pub(crate) fn options() -> TraceOptions {
+    TRACER.with(|tracer| tracer.borrow().options)
 }
 
-pub fn info(message: String) {
-    if INFO {
-        println!("[INFO] {:?}", message);
-    }
+// writes to the trace sink whatever options are enabled, callers check them.
+pub fn trace(message: String) {
+    TRACER.with(|tracer| {
+        // a broken trace sink shouldn't stop the program being traced
+        let _ = writeln!(tracer.borrow_mut().sink, "{}", message);
+    });
 }
 
-pub(crate) fn print_value(value: &Value, new_line: bool) {
-    match value {
-        Value::Obj(obj) => match obj {
-            Obj::Str(fat_ptr) => unsafe {
-                let str = memory::read_string(fat_ptr.ptr, fat_ptr.size);
-                debug(format!("constant value: {:?}", str), new_line);
-            },
-            _ => debug(format!("constant value: {:?}", obj), new_line),
-        },
-        _ => debug(format!("constant value: {:?}", value), new_line),
-    }
+// details of what the vm is doing, only formatted and written when
+// execution is traced.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::debug::options().exec {
+            $crate::debug::trace(format!("[INFO] {}", format_args!($($arg)*)));
+        }
+    };
 }
@@ -0,0 +1,259 @@
+// Human readable listings of compiled code in the format clox uses, and the
+// same information as json for tools.
+use crate::chunk::{self, Chunk, Instruction, Operand};
+use crate::common::{Function, Obj, OpCode, Value};
+use crate::memory;
+
+// listing of a single chunk, one instruction per line.
+pub(crate) fn listing(chunk: &Chunk, name: &str) -> String {
+    let mut lines = vec![format!("== {} ==", name)];
+    for instruction in chunk.instructions() {
+        lines.push(instruction_listing(chunk, &instruction));
+    }
+    lines.join("\n")
+}
+
+// listings of `function` followed by every function nested in it.
+pub(crate) fn function_listing(function: &Function) -> String {
+    functions(function)
+        .iter()
+        .map(|function| listing(&function.chunk, &function_name(function)))
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+// offset, the line or `|` when it's the same as the byte before, the opcode
+// and its operands, closures get an extra line for every capture.
+pub(crate) fn instruction_listing(chunk: &Chunk, instruction: &Instruction) -> String {
+    let offset = instruction.offset;
+    let line = instruction.location.line;
+    let line = if offset > 0 && chunk.location_of(offset - 1).line == line {
+        "   |".to_string()
+    } else {
+        format!("{:>4}", line)
+    };
+    let prefix = format!("{:04} {} ", offset, line);
+    let name = match instruction.opcode {
+        Some(opcode) => opcode_name(opcode),
+        None => return format!("{}Unknown opcode {}", prefix, instruction.byte),
+    };
+    match &instruction.operand {
+        Operand::None => format!("{}{}", prefix, name),
+        Operand::Index(index) => format!("{}{:<25} {:>4}", prefix, name, index),
+        Operand::Constant(index) => format!(
+            "{}{:<25} {:>4} '{}'",
+            prefix,
+            name,
+            index,
+            chunk.constants.get(*index)
+        ),
+        Operand::Jump(target) => format!("{}{:<25} {:>4} -> {}", prefix, name, offset, target),
+        Operand::Closure(index, captures) => {
+            let mut lines = vec![format!(
+                "{}{:<25} {:>4} {}",
+                prefix,
+                name,
+                index,
+                chunk.constants.get(*index)
+            )];
+            let width = chunk::operand_width(instruction.opcode.is_some_and(OpCode::is_long));
+            for (i, (is_local, index)) in captures.iter().enumerate() {
+                let kind = if *is_local { "local" } else { "upvalue" };
+                lines.push(format!(
+                    "{:04}    |                           {} {}",
+                    offset + 1 + width + i * (1 + width),
+                    kind,
+                    index
+                ));
+            }
+            lines.join("\n")
+        }
+    }
+}
+
+// GetLocalVariable becomes OP_GET_LOCAL_VARIABLE
+fn opcode_name(opcode: OpCode) -> String {
+    let mut name = String::from("OP");
+    for c in format!("{:?}", opcode).chars() {
+        if c.is_uppercase() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+    name
+}
+
+fn function_name(function: &Function) -> String {
+    match &function.name {
+        Some(name) => memory::read_string(name.ptr, name.size),
+        None => "<script>".to_string(),
+    }
+}
+
+// `function` and all functions in its constants, depth first.
+fn functions(function: &Function) -> Vec<&Function> {
+    let mut functions = vec![function];
+    for constant in function.chunk.constants.values.iter() {
+        if let Value::Obj(Obj::Fun(nested)) = constant {
+            functions.extend(self::functions(unsafe { &**nested }));
+        }
+    }
+    functions
+}
+
+// {"functions": [{"name", "arity", "upvalues", "instructions": [...]}]}, every
+// instruction has its offset, line, column and opcode, which is null for unknown
+// bytes, plus fields for its operands.
+pub(crate) fn function_json(function: &Function) -> String {
+    let functions: Vec<String> = functions(function)
+        .iter()
+        .map(|function| {
+            let instructions: Vec<String> = function
+                .chunk
+                .instructions()
+                .iter()
+                .map(|instruction| instruction_json(&function.chunk, instruction))
+                .collect();
+            format!(
+                "{{\"name\":{},\"arity\":{},\"upvalues\":{},\"instructions\":[{}]}}",
+                json_string(&function_name(function)),
+                function.arity,
+                function.up_value_count,
+                instructions.join(",")
+            )
+        })
+        .collect();
+    format!("{{\"functions\":[{}]}}", functions.join(","))
+}
+
+fn instruction_json(chunk: &Chunk, instruction: &Instruction) -> String {
+    let mut fields = vec![
+        format!("\"offset\":{}", instruction.offset),
+        format!("\"line\":{}", instruction.location.line),
+        format!("\"column\":{}", instruction.location.column),
+    ];
+    match instruction.opcode {
+        Some(opcode) => fields.push(format!("\"opcode\":{}", json_string(&opcode_name(opcode)))),
+        None => {
+            fields.push("\"opcode\":null".to_string());
+            fields.push(format!("\"byte\":{}", instruction.byte));
+        }
+    }
+    match &instruction.operand {
+        Operand::None => (),
+        Operand::Index(index) => fields.push(format!("\"operand\":{}", index)),
+        Operand::Constant(index) => {
+            fields.push(format!("\"constant\":{}", index));
+            fields.push(format!(
+                "\"value\":{}",
+                json_value(&chunk.constants.get(*index))
+            ));
+        }
+        Operand::Jump(target) => fields.push(format!("\"target\":{}", target)),
+        Operand::Closure(index, captures) => {
+            fields.push(format!("\"constant\":{}", index));
+            fields.push(format!(
+                "\"value\":{}",
+                json_value(&chunk.constants.get(*index))
+            ));
+            let captures: Vec<String> = captures
+                .iter()
+                .map(|(is_local, index)| format!("{{\"local\":{},\"index\":{}}}", is_local, index))
+                .collect();
+            fields.push(format!("\"captures\":[{}]", captures.join(",")));
+        }
+    }
+    format!("{{{}}}", fields.join(","))
+}
+
+fn json_value(value: &Value) -> String {
+    match value {
+        Value::Number(number) if number.is_finite() => number.to_string(),
+        Value::Boolean(boolean) => boolean.to_string(),
+        Value::Number(_) | Value::Missing => "null".to_string(),
+        Value::Obj(_) => json_string(&value.to_string()),
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::memory::Heap;
+    use crate::scanner::Scanner;
+
+    fn compile(source: &str, heap: &mut Heap) -> Function {
+        let scanner = Scanner::init(0, 0, vec![]);
+        let function = Compiler::init(scanner, heap).compile(source.to_string());
+        Into::<Function>::into(function.unwrap())
+    }
+
+    #[test]
+    fn lists_nested_functions_like_clox() {
+        let mut heap = Heap::init();
+        let source = "fun outer(a) {\n  fun inner() { return a; }\n  if (a) return inner;\n}";
+        assert_eq!(
+            function_listing(&compile(source, &mut heap)),
+            "\
+== <script> ==
+0000    4 OP_CLOSURE                   1 <fn outer>
+0002    | OP_DEFINE_GLOBAL_VARIABLE    0 'outer'
+0004    | OP_NIL
+0005    | OP_RETURN
+
+== outer ==
+0000    2 OP_CLOSURE                   0 <fn inner>
+0002    |                           local 1
+0004    3 OP_GET_LOCAL_VARIABLE        1
+0006    | OP_JUMP_IF_FALSE             6 -> 16
+0009    | OP_POP
+0010    | OP_GET_LOCAL_VARIABLE        2
+0012    | OP_RETURN
+0013    | OP_JUMP                     13 -> 17
+0016    | OP_POP
+0017    4 OP_POP
+0018    | OP_CLOSE_UP_VALUE
+0019    | OP_NIL
+0020    | OP_RETURN
+
+== inner ==
+0000    2 OP_GET_UP_VALUE              0
+0002    | OP_RETURN
+0003    | OP_NIL
+0004    | OP_RETURN"
+        );
+    }
+
+    #[test]
+    fn escapes_strings_in_json() {
+        let mut heap = Heap::init();
+        // lox has no escapes, the quote and backslash end up in the string as is,
+        // the two strings are joined when compiling
+        let json = function_json(&compile("print \"a\\b\nc\" + \"'\";", &mut heap));
+        assert_eq!(
+            json,
+            "{\"functions\":[{\"name\":\"<script>\",\"arity\":0,\"upvalues\":0,\"instructions\":[\
+             {\"offset\":0,\"line\":2,\"column\":4,\"opcode\":\"OP_CONSTANT\",\"constant\":0,\"value\":\"a\\\\b\\nc'\"},\
+             {\"offset\":2,\"line\":2,\"column\":9,\"opcode\":\"OP_PRINT\"},\
+             {\"offset\":3,\"line\":2,\"column\":10,\"opcode\":\"OP_NIL\"},\
+             {\"offset\":4,\"line\":2,\"column\":10,\"opcode\":\"OP_RETURN\"}]}]}"
+        );
+    }
+}
@@ -1,102 +1,175 @@
-use crate::common::FatPointer;
+use crate::common::{FatPointer, Obj, Value};
+use crate::hasher;
 use crate::memory;
-use std::borrow::BorrowMut;
 use std::fmt::Debug;
+
+// anything a table can use as key, the hash of equal keys has to be the same
+pub(crate) trait TableKey: Clone + Debug + PartialEq {
+    fn hash_code(&self) -> u32;
+}
+
+// strings carry their hash with them
+impl TableKey for FatPointer {
+    fn hash_code(&self) -> u32 {
+        self.hash
+    }
+}
+
+// keys of lox maps, other objects are rejected before they get here
+impl TableKey for Value {
+    fn hash_code(&self) -> u32 {
+        match self {
+            Value::Obj(Obj::Str(string)) => string.hash,
+            Value::Number(number) => hasher::hash_number(*number),
+            Value::Boolean(boolean) => *boolean as u32 + 1,
+            Value::Missing | Value::Obj(_) => 0,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
-pub(crate) enum Entry<T> {
-    Occupied(FatPointer, T),
+pub(crate) enum Entry<T, K = FatPointer> {
+    Occupied(K, T),
     Vacant,
     TombStone,
 }
 
 #[derive(Debug)]
-pub(crate) struct Table<T>
+pub(crate) struct Table<T, K = FatPointer>
 where
     T: Debug,
     T: Clone,
+    K: TableKey,
 {
-    entries: Vec<Entry<T>>,
+    entries: Vec<Entry<T, K>>,
     capacity: usize,
+    // number of keys, tombstones are counted separately
     size: usize,
+    tombstones: usize,
     load_factor: usize,
 }
 
-impl<T> Table<T>
+impl<T, K> Table<T, K>
 where
     T: Clone,
     T: Debug,
+    K: TableKey,
 {
-    pub(crate) fn init(capacity: usize) -> Table<T> {
-        let mut entries: Vec<Entry<T>> = vec![];
+    pub(crate) fn init(capacity: usize) -> Table<T, K> {
+        let mut entries: Vec<Entry<T, K>> = vec![];
         entries.resize(capacity, Entry::Vacant);
         Table {
             entries,
             capacity,
             size: 0,
+            tombstones: 0,
             load_factor: 70,
         }
     }
 
-    pub(crate) fn insert(&mut self, key: FatPointer, value: T) -> bool {
+    // returns true if the key was already in the table and got overwritten
+    pub(crate) fn insert(&mut self, key: K, value: T) -> bool {
+        // the key may sit behind a tombstone, reuse its bucket then
+        if let Some(bucket) = self.find_entry_index(&key) {
+            self.entries[bucket] = Entry::Occupied(key, value);
+            return true;
+        }
         self.ensure_capacity();
         let bucket = self.find_bucket(&key, &self.entries);
-        let new_value = matches!(&self.entries[bucket], Entry::Occupied(_, _));
+        if matches!(self.entries[bucket], Entry::TombStone) {
+            self.tombstones -= 1;
+        }
         self.entries[bucket] = Entry::Occupied(key, value);
         self.size += 1;
-        new_value
+        false
     }
 
-    pub(crate) fn get(&self, key: FatPointer) -> Option<&T> {
-        let entry = self.find_entry(&key).unwrap();
-        match entry {
-            Entry::Occupied(value, data) => Some(data),
+    pub(crate) fn get(&self, key: K) -> Option<&T> {
+        match self.find_entry(&key) {
+            Some(Entry::Occupied(_, data)) => Some(data),
             _ => None,
         }
     }
 
-    pub(crate) fn get_mut(&mut self, key: FatPointer) -> Option<&mut T> {
-        let entry = self.find_entry_mut(&key).unwrap();
-        match entry {
-            Entry::Occupied(value, data) => Some(data),
+    #[allow(dead_code)]
+    pub(crate) fn get_mut(&mut self, key: K) -> Option<&mut T> {
+        match self.find_entry_mut(&key) {
+            Some(Entry::Occupied(_, data)) => Some(data),
             _ => None,
         }
     }
 
-    pub(crate) fn delete(&mut self, key: FatPointer) -> Option<T> {
-        let bucket = self.find_bucket(&key, &self.entries);
+    pub(crate) fn delete(&mut self, key: K) -> Option<T> {
+        let bucket = self.find_entry_index(&key)?;
         let value = self.get_at_index(bucket);
-        if value.is_some() {
-            self.insert_tombstone(bucket);
-        }
+        self.insert_tombstone(bucket);
         value
     }
 
+    // copies every entry of this table into `to`, existing keys are overwritten.
+    pub(crate) fn add_all(&self, to: &mut Table<T, K>) {
+        for entry in self.entries.iter() {
+            if let Entry::Occupied(key, value) = entry {
+                to.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    // number of keys in the table
+    pub(crate) fn len(&self) -> usize {
+        self.size
+    }
+
+    // bytes taken by the buckets, the heap counts them for the object
+    // owning the table
+    pub(crate) fn allocated_size(&self) -> usize {
+        self.entries.capacity() * std::mem::size_of::<Entry<T, K>>()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &T)> {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::Occupied(key, value) => Some((key, value)),
+            _ => None,
+        })
+    }
+
+    // deletes every entry whose key doesn't satisfy `keep`.
+    pub(crate) fn retain(&mut self, keep: impl Fn(&K) -> bool) {
+        for bucket in 0..self.entries.len() {
+            if let Entry::Occupied(key, _) = &self.entries[bucket] {
+                if !keep(key) {
+                    self.insert_tombstone(bucket);
+                }
+            }
+        }
+    }
+
     fn insert_tombstone(&mut self, bucket: usize) {
         self.entries[bucket] = Entry::TombStone;
+        self.size -= 1;
+        self.tombstones += 1;
     }
 
     fn get_at_index(&mut self, bucket: usize) -> Option<T> {
-        let entry = &self.entries[bucket];
-        return match entry {
+        match &self.entries[bucket] {
             Entry::Occupied(_, value) => Some(value.clone()),
             _ => None,
-        };
+        }
     }
 
     fn ensure_capacity(&mut self) {
-        if ((self.size + 1) / self.capacity) * 100 > self.load_factor {
+        // tombstones take up buckets too, a lookup only stops at a vacant one
+        if (self.size + self.tombstones + 1) * 100 > self.capacity * self.load_factor {
             self.capacity = (self.capacity * 2) + 1;
-            let mut temp_entries: Vec<Entry<T>> = vec![];
+            let mut temp_entries: Vec<Entry<T, K>> = vec![];
             temp_entries.resize(self.capacity, Entry::Vacant);
             self.size = 0;
+            self.tombstones = 0;
             for entry in self.entries.iter() {
-                match entry {
-                    Entry::Occupied(key, value) => {
-                        let bucket = self.find_bucket(key, &temp_entries);
-                        temp_entries[bucket] = Entry::Occupied(key.clone(), value.clone());
-                        self.size += 1;
-                    }
-                    _ => (),
+                if let Entry::Occupied(key, value) = entry {
+                    let bucket = self.find_bucket(key, &temp_entries);
+                    temp_entries[bucket] = Entry::Occupied(key.clone(), value.clone());
+                    self.size += 1;
                 }
             }
 
@@ -104,8 +177,8 @@ where
         }
     }
 
-    fn find_bucket(&self, key: &FatPointer, entries: &Vec<Entry<T>>) -> usize {
-        let mut bucket = key.hash % (self.capacity as u32);
+    fn find_bucket(&self, key: &K, entries: &[Entry<T, K>]) -> usize {
+        let mut bucket = key.hash_code() % (self.capacity as u32);
 
         while self.is_occupied(bucket, key, entries) {
             bucket = (bucket + 1) % (self.capacity as u32);
@@ -114,51 +187,25 @@ where
         bucket as usize
     }
 
-    pub(crate) fn dump(&self) {
-        println!("{:?}", self.entries);
-    }
-
-    pub(crate) fn find_entry_with_value(&self, str_value: &str, hash: u32) -> Option<&FatPointer> {
-        let mut bucket = hash % (self.capacity as u32);
-        loop {
-            return match &self.entries[bucket as usize] {
-                Entry::Occupied(existing, _) => {
-                    // if key is same we will use the same index
-                    if memory::read_string(existing.ptr, existing.size).eq(str_value) {
-                        Some(&existing)
-                    } else {
-                        bucket = (bucket + 1) % (self.capacity as u32);
-                        continue;
-                    }
-                }
-                Entry::Vacant => None,
-                Entry::TombStone => {
-                    bucket = (bucket + 1) % (self.capacity as u32);
-                    continue;
-                }
-            };
-        }
-    }
-
-    pub(crate) fn find_entry(&self, key: &FatPointer) -> Option<&Entry<T>> {
+    pub(crate) fn find_entry(&self, key: &K) -> Option<&Entry<T, K>> {
         let index = self.find_entry_index(key);
-        println!("Entry index: {:?}", index);
-        return match index {
+        match index {
             Some(index) => self.entries.get(index),
             None => None,
-        };
+        }
     }
 
-    fn find_entry_mut(&mut self, key: &FatPointer) -> Option<&mut Entry<T>> {
+    #[allow(dead_code)]
+    fn find_entry_mut(&mut self, key: &K) -> Option<&mut Entry<T, K>> {
         let index = self.find_entry_index(key);
-        return match index {
+        match index {
             Some(index) => self.entries.get_mut(index),
             None => None,
-        };
+        }
     }
 
-    fn find_entry_index(&self, key: &FatPointer) -> Option<usize> {
-        let mut bucket = key.hash % (self.capacity as u32);
+    fn find_entry_index(&self, key: &K) -> Option<usize> {
+        let mut bucket = key.hash_code() % (self.capacity as u32);
         loop {
             let entry = self.entries.get(bucket as usize);
             return match entry {
@@ -182,24 +229,44 @@ where
         }
     }
 
-    fn is_occupied(&self, bucket: u32, key: &FatPointer, entries: &Vec<Entry<T>>) -> bool {
+    fn is_occupied(&self, bucket: u32, key: &K, entries: &[Entry<T, K>]) -> bool {
         match &entries[bucket as usize] {
             Entry::Occupied(existing, _) => {
                 // if key is same we will use the same index
-                if memory::eq(existing.ptr, key.ptr) {
-                    false
-                } else {
-                    true
-                }
+                existing != key
             }
             Entry::Vacant | Entry::TombStone => false,
         }
     }
 }
 
-#[derive(Debug, Clone)]
-struct TestValue {
-    id: u32,
+// the string table looks strings up by content before they are interned
+impl<T> Table<T>
+where
+    T: Clone,
+    T: Debug,
+{
+    pub(crate) fn find_entry_with_value(&self, str_value: &str, hash: u32) -> Option<&FatPointer> {
+        let mut bucket = hash % (self.capacity as u32);
+        loop {
+            return match &self.entries[bucket as usize] {
+                Entry::Occupied(existing, _) => {
+                    // if key is same we will use the same index
+                    if memory::read_string(existing.ptr, existing.size).eq(str_value) {
+                        Some(existing)
+                    } else {
+                        bucket = (bucket + 1) % (self.capacity as u32);
+                        continue;
+                    }
+                }
+                Entry::Vacant => None,
+                Entry::TombStone => {
+                    bucket = (bucket + 1) % (self.capacity as u32);
+                    continue;
+                }
+            };
+        }
+    }
 }
 
 #[cfg(test)]
@@ -207,9 +274,14 @@ mod tests {
     use super::*;
     use crate::hasher::hash;
 
+    #[derive(Debug, Clone)]
+    struct TestValue {
+        id: u32,
+    }
+
     fn create_fat_ptr(value: &mut &str) -> FatPointer {
         FatPointer {
-            ptr: value.to_string().as_mut_ptr(),
+            ptr: Box::leak(value.to_string().into_boxed_str()).as_mut_ptr(),
             size: value.len(),
             hash: hash(value),
         }
@@ -273,12 +345,89 @@ mod tests {
         assert_eq!(map.get(one.clone()), None);
     }
 
+    #[test]
+    fn can_copy_entries_to_another_table() {
+        let mut from = Table::init(2);
+        let mut to = Table::init(2);
+        let one = create_fat_ptr(&mut "one");
+        let two = create_fat_ptr(&mut "two");
+
+        from.insert(one.clone(), true);
+        to.insert(one.clone(), false);
+        to.insert(two.clone(), false);
+        from.add_all(&mut to);
+
+        assert_eq!(to.get(one.clone()), Some(&true));
+        assert_eq!(to.get(two.clone()), Some(&false));
+    }
+
+    #[test]
+    fn can_retain_matching_keys() {
+        let mut map = Table::init(4);
+        let one = create_fat_ptr(&mut "one");
+        let two = create_fat_ptr(&mut "two");
+
+        map.insert(one.clone(), true);
+        map.insert(two.clone(), false);
+        map.retain(|key| key.ptr == one.ptr);
+
+        assert_eq!(map.get(one.clone()), Some(&true));
+        assert_eq!(map.get(two.clone()), None);
+        assert_eq!(map.iter().count(), 1);
+    }
+
+    #[test]
+    fn keeps_one_entry_for_a_key_behind_a_tombstone() {
+        let mut map = Table::init(8);
+        let one = FatPointer {
+            hash: 0,
+            ..create_fat_ptr(&mut "one")
+        };
+        let two = FatPointer {
+            hash: 0,
+            ..create_fat_ptr(&mut "two")
+        };
+
+        map.insert(one.clone(), 1);
+        map.insert(two.clone(), 2);
+        map.delete(one.clone());
+        map.insert(two.clone(), 3);
+
+        assert_eq!(map.get(two.clone()), Some(&3));
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.delete(two.clone()), Some(3));
+        assert_eq!(map.len(), 0);
+    }
+
+    #[test]
+    fn grows_instead_of_filling_up_with_tombstones() {
+        let mut map: Table<bool, Value> = Table::init(3);
+        for i in 0..10 {
+            map.insert(Value::from(i as f64), true);
+            map.delete(Value::from(i as f64));
+        }
+
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.get(Value::from(42.0)), None);
+    }
+
+    #[test]
+    fn can_use_values_as_keys() {
+        let mut map: Table<bool, Value> = Table::init(4);
+        map.insert(Value::from(0.0), true);
+        map.insert(Value::Missing, false);
+
+        assert_eq!(map.get(Value::from(-0.0)), Some(&true));
+        assert_eq!(map.get(Value::Missing), Some(&false));
+        assert_eq!(map.get(Value::from(false)), None);
+    }
+
     #[test]
     fn can_expand_capacity_as_required() {
         let mut map = Table::init(1);
         let one = create_fat_ptr(&mut "one");
         let two = create_fat_ptr(&mut "two");
-        let _three = create_fat_ptr(&mut "three");
+        let three = create_fat_ptr(&mut "three");
 
         map.insert(one.clone(), true);
         assert_eq!(map.capacity, 3);
@@ -286,8 +435,27 @@ mod tests {
         map.insert(two.clone(), false);
         assert_eq!(map.capacity, 3);
 
+        // overwriting a key doesn't need room for another one
         map.insert(two.clone(), true);
+        assert_eq!(map.capacity, 3);
+        assert_eq!(map.len(), 2);
+
+        map.insert(three.clone(), true);
         assert_eq!(map.capacity, 7);
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn grows_once_past_the_load_factor() {
+        let mut map: Table<bool, Value> = Table::init(10);
+        for i in 0..7 {
+            map.insert(Value::from(i as f64), true);
+        }
+        assert_eq!(map.capacity, 10);
+
+        map.insert(Value::from(7.0), true);
+        assert_eq!(map.capacity, 21);
+        assert_eq!(map.len(), 8);
     }
 
     #[test]
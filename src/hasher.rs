@@ -1,14 +1,19 @@
 //fnv hash impl basic
 pub(crate) fn hash(value: &str) -> u32 {
     let mut hash = 2166136261;
-    let chars: Vec<char> = value.chars().collect();
-    for i in 0..value.len() {
-        hash ^= chars[i] as u32;
+    for c in value.chars() {
+        hash ^= c as u32;
         hash = hash.wrapping_mul(16777619);
     }
     hash
 }
 
+// equal numbers hash the same, 0 and -0 included
+pub(crate) fn hash_number(value: f64) -> u32 {
+    let bits = if value == 0.0 { 0 } else { value.to_bits() };
+    (bits ^ (bits >> 32)) as u32
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -17,4 +22,10 @@ mod tests {
     fn can_calculate_hash() {
         assert_eq!(hash("one"), 3123124719);
     }
+
+    #[test]
+    fn zero_has_one_hash() {
+        assert_eq!(hash_number(0.0), hash_number(-0.0));
+        assert_ne!(hash_number(1.0), hash_number(2.0));
+    }
 }
@@ -0,0 +1,289 @@
+// Runs every `.lox` script under `tests/` and checks it against the
+// annotations in its comments:
+//
+//   // expect: <line>                   line the script prints
+//   // expect runtime error: <message>  script fails at this line
+//   // Error at '<lexeme>': <message>   compile error reported for this line
+//   // [line <n>] Error ...             compile error reported for another line
+//
+// Each script runs three times: as is, with the gc stressed and compiled to
+// bytecode which is read back and verified before it runs.
+use crate::bytecode;
+use crate::disassembler;
+use crate::vm::{InterpretResult, VM};
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// output sink which can still be read after it's handed to the vm
+#[derive(Clone, Default)]
+pub(crate) struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SharedOutput {
+    pub(crate) fn contents(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+// what a script did, or is annotated to do
+#[derive(Debug, Default, PartialEq)]
+struct Outcome {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    // message and the line it was raised on
+    runtime_error: Option<(String, u32)>,
+}
+
+const EXPECT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+const ERROR: &str = "// Error";
+const ERROR_WITH_LINE: &str = "// [line ";
+
+fn parse_annotations(source: &str) -> Outcome {
+    let mut expected = Outcome::default();
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index as u32 + 1;
+        if let Some(at) = line.find(EXPECT) {
+            expected.output.push(line[at + EXPECT.len()..].to_string());
+        } else if let Some(at) = line.find(EXPECT_RUNTIME_ERROR) {
+            let message = line[at + EXPECT_RUNTIME_ERROR.len()..].to_string();
+            expected.runtime_error = Some((message, line_number));
+        } else if let Some(at) = line.find(ERROR) {
+            // skip "// " so the rest reads like the reported error
+            let error = &line[at + 3..];
+            expected
+                .compile_errors
+                .push(format!("[line {}] {}", line_number, error));
+        } else if let Some(at) = line.find(ERROR_WITH_LINE) {
+            expected.compile_errors.push(line[at + 3..].to_string());
+        }
+    }
+    expected
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    Source,
+    StressGc,
+    Bytecode,
+}
+
+fn run_script(source: &str, mode: Mode) -> Outcome {
+    let mut vm = VM::init();
+    vm.set_stress_gc(matches!(mode, Mode::StressGc));
+    let output = SharedOutput::default();
+    vm.set_output(Box::new(output.clone()));
+
+    let result = match mode {
+        Mode::Source | Mode::StressGc => vm.interpret(source.to_string()),
+        Mode::Bytecode => match vm.compile(source.to_string()) {
+            Ok(function) => vm.interpret_bytecode(&bytecode::write(&function)),
+            Err(errors) => InterpretResult::CompileError(errors),
+        },
+    };
+    let mut actual = Outcome::default();
+    match result {
+        InterpretResult::Ok => (),
+        InterpretResult::CompileError(errors) => {
+            actual.compile_errors = errors.iter().map(|error| without_column(error)).collect()
+        }
+        InterpretResult::RuntimeError(error) => {
+            let line = error.trace.first().map_or(0, |trace_line| trace_line.line);
+            actual.runtime_error = Some((error.message, line));
+        }
+    }
+    actual.output = output.contents().lines().map(String::from).collect();
+    actual
+}
+
+// annotations only have the line, "[line 1, column 7] Error" becomes
+// "[line 1] Error"
+fn without_column(error: &str) -> String {
+    match (error.find(", column "), error.find(']')) {
+        (Some(start), Some(end)) if start < end => format!("{}{}", &error[..start], &error[end..]),
+        _ => error.to_string(),
+    }
+}
+
+// lists differences line by line, `-` is expected and `+` is what happened.
+fn diff(expected: &Outcome, actual: &Outcome) -> String {
+    let mut lines = Vec::new();
+    let mut diff_lines = |name: &str, expected: &[String], actual: &[String]| {
+        for index in 0..expected.len().max(actual.len()) {
+            let (expected, actual) = (expected.get(index), actual.get(index));
+            if expected != actual {
+                if let Some(expected) = expected {
+                    lines.push(format!("  - {} {}: {}", name, index + 1, expected));
+                }
+                if let Some(actual) = actual {
+                    lines.push(format!("  + {} {}: {}", name, index + 1, actual));
+                }
+            }
+        }
+    };
+    diff_lines("output", &expected.output, &actual.output);
+    diff_lines(
+        "compile error",
+        &expected.compile_errors,
+        &actual.compile_errors,
+    );
+    if expected.runtime_error != actual.runtime_error {
+        lines.push(format!("  - runtime error: {:?}", expected.runtime_error));
+        lines.push(format!("  + runtime error: {:?}", actual.runtime_error));
+    }
+    lines.join("\n")
+}
+
+fn find_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            find_scripts(&path, scripts);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            scripts.push(path);
+        }
+    }
+}
+
+#[test]
+fn scripts_match_their_annotations() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let mut scripts = Vec::new();
+    find_scripts(&root, &mut scripts);
+    scripts.sort();
+    assert!(!scripts.is_empty(), "No scripts found in {:?}", root);
+
+    let mut failures = Vec::new();
+    for script in scripts.iter() {
+        let source = fs::read_to_string(script).unwrap();
+        let expected = parse_annotations(&source);
+        for mode in [Mode::Source, Mode::StressGc, Mode::Bytecode] {
+            let actual = run_script(&source, mode);
+            if actual != expected {
+                failures.push(format!(
+                    "{} ({:?})\n{}",
+                    script.strip_prefix(&root).unwrap().display(),
+                    mode,
+                    diff(&expected, &actual)
+                ));
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} runs failed:\n{}",
+        failures.len(),
+        scripts.len() * 3,
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn can_parse_annotations() {
+    let source = "print 1; // expect: 1\n\
+                  nil + 1; // expect runtime error: Operands must be numbers.\n\
+                  var = 2; // Error at '=': Expect variable name.\n\
+                  // [line 5] Error at end: Expect ';' after value.";
+    let expected = parse_annotations(source);
+    assert_eq!(expected.output, vec!["1"]);
+    assert_eq!(
+        expected.runtime_error,
+        Some(("Operands must be numbers.".to_string(), 2))
+    );
+    assert_eq!(
+        expected.compile_errors,
+        vec![
+            "[line 3] Error at '=': Expect variable name.",
+            "[line 5] Error at end: Expect ';' after value."
+        ]
+    );
+}
+
+#[test]
+fn jumps_over_more_than_64k_of_code() {
+    // every statement compiles to about 15 bytes, the branches, the jump
+    // over the else branch and the loop all cover more than u16::MAX bytes
+    let statements = |step: u32| format!("x = x + {};\n", step).repeat(10_000);
+    let source = format!(
+        "fun f() {{\n  var x = 0;\n  var i = 0;\n  while (i < 2) {{\n    \
+         if (i == 0) {{\n{}}} else {{\n{}}}\n    i = i + 1;\n  }}\n  return x;\n}}\n\
+         print f();",
+        statements(1),
+        statements(2)
+    );
+
+    let mut vm = VM::init();
+    let listing = disassembler::function_listing(&vm.compile(source.clone()).unwrap());
+    for opcode in ["OP_JUMP_IF_FALSE_LONG", "OP_JUMP_LONG", "OP_LOOP_LONG"] {
+        assert!(listing.contains(opcode), "{} is not used", opcode);
+    }
+    for mode in [Mode::Source, Mode::Bytecode] {
+        assert_eq!(run_script(&source, mode).output, vec!["30000"]);
+    }
+}
+
+#[test]
+fn uses_long_operands_past_256_names_and_locals() {
+    let names = |prefix: &str| {
+        (0..300)
+            .map(|i| format!("{}{}", prefix, i))
+            .collect::<Vec<String>>()
+    };
+    let declare = |prefix: &str| {
+        names(prefix)
+            .iter()
+            .enumerate()
+            .map(|(i, name)| format!("var {} = {};\n", name, i))
+            .collect::<String>()
+    };
+    let sum = |prefix: &str| names(prefix).join(" + ");
+    // the closure captures 300 locals, the last ones have long indices in
+    // both functions
+    let source = format!(
+        "{}g0 = 5;\nprint g0 + g299;\n\
+         fun f() {{\n{}l299 = l299 + 1;\n\
+         fun inner() {{\nvar sum = {};\nl299 = 7;\nreturn sum + l299;\n}}\n\
+         return inner() + l299;\n}}\nprint f();\n\
+         class C {{ get() {{ return this.field; }} }}\n\
+         var c = C();\nc.field = \"field\";\nprint c.field;\nprint c.get();",
+        declare("g"),
+        declare("l"),
+        sum("l")
+    );
+
+    let mut vm = VM::init();
+    let listing = disassembler::function_listing(&vm.compile(source.clone()).unwrap());
+    for opcode in [
+        "OP_DEFINE_GLOBAL_VARIABLE_LONG",
+        "OP_GET_GLOBAL_VARIABLE_LONG",
+        "OP_SET_GLOBAL_VARIABLE_LONG",
+        "OP_GET_LOCAL_VARIABLE_LONG",
+        "OP_SET_LOCAL_VARIABLE_LONG",
+        "OP_GET_UP_VALUE_LONG",
+        "OP_SET_UP_VALUE_LONG",
+        "OP_CLOSURE_LONG",
+        "OP_CLASS_LONG",
+        "OP_METHOD_LONG",
+        "OP_GET_PROPERTY_LONG",
+        "OP_SET_PROPERTY_LONG",
+    ] {
+        assert!(listing.contains(opcode), "{} is not used", opcode);
+    }
+    // 0 + 1 + ... + 298 + 300, then 7 twice
+    let expected = vec!["304", "44865", "field", "field"];
+    for mode in [Mode::Source, Mode::StressGc, Mode::Bytecode] {
+        assert_eq!(run_script(&source, mode).output, expected, "{:?}", mode);
+    }
+}
@@ -1,78 +1,234 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::{env, fs};
 
-use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+mod bytecode;
 mod chunk;
 #[macro_use]
 mod common;
 mod compiler;
+#[macro_use]
 mod debug;
+mod disassembler;
 mod hash_map;
 mod hasher;
+#[cfg(test)]
+mod lox_tests;
 mod memory;
 mod metrics;
+mod native;
+mod optimizer;
+mod repl;
 mod scanner;
 mod value;
+mod verifier;
 mod vm;
+
+use debug::TraceOptions;
+use common::Function;
+use vm::InterpretResult;
+
 #[derive(Parser)]
 struct Cli {
-    // source file path
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// source file path
     #[clap(parse(from_os_str), default_value = "")]
     path: PathBuf,
+    /// run the garbage collector before every allocation
+    #[clap(long)]
+    stress_gc: bool,
+    /// trace every executed instruction
+    #[clap(long)]
+    trace_exec: bool,
+    /// dump the value stack before every instruction
+    #[clap(long)]
+    trace_stack: bool,
+    /// disassemble functions after compiling them
+    #[clap(long)]
+    print_code: bool,
+    /// write traces to this file instead of stderr
+    #[clap(long, parse(from_os_str))]
+    trace_file: Option<PathBuf>,
 }
 
-fn run_file(path: PathBuf) {
-    let mut file = fs::File::open(&path).expect("Unable to read file");
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)
-        .expect("Something went wrong while reading the file.");
+#[derive(Subcommand)]
+enum Command {
+    /// compile a script to bytecode without running it
+    Compile {
+        #[clap(parse(from_os_str))]
+        path: PathBuf,
+        /// defaults to the script path with a .loxc extension
+        #[clap(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// list the bytecode of a script or .loxc file and every function in it
+    Disasm {
+        #[clap(parse(from_os_str))]
+        path: PathBuf,
+        /// print json instead of a listing
+        #[clap(long)]
+        json: bool,
+    },
+}
 
-    if contents.len() < file.metadata().unwrap().len().try_into().unwrap() {
-        eprintln!("Could not read file: {:?}", path);
-        std::process::exit(74);
-    }
-    let mut vm = vm::VM::init();
-    vm.interpret(contents.to_string());
+fn configure_tracing(args: &Cli) -> Result<(), std::io::Error> {
+    let options = TraceOptions {
+        exec: args.trace_exec,
+        stack: args.trace_stack,
+        code: args.print_code,
+    };
+    let sink: Box<dyn Write> = match &args.trace_file {
+        Some(path) => Box::new(fs::File::create(path)?),
+        None => Box::new(std::io::stderr()),
+    };
+    debug::configure(options, sink);
+    Ok(())
 }
 
-struct Repl<'a> {
-    vm: &'a mut vm::VM,
+// exit codes follow clox, which borrowed them from BSD sysexits.h
+const EXIT_COMPILE_ERROR: i32 = 65;
+const EXIT_RUNTIME_ERROR: i32 = 70;
+const EXIT_IO_ERROR: i32 = 74;
+
+fn read_file(path: &PathBuf) -> Result<Vec<u8>, i32> {
+    fs::read(path).map_err(|error| {
+        eprintln!("Could not read file {:?}: {}", path, error);
+        EXIT_IO_ERROR
+    })
 }
 
-impl<'a> Repl<'a> {
-    fn init(vm: &'a mut vm::VM) -> Repl<'a> {
-        Repl { vm }
+// runs lox source or bytecode written by `rlox compile`
+fn run_file(path: PathBuf, stress_gc: bool) -> i32 {
+    let contents = match read_file(&path) {
+        Ok(contents) => contents,
+        Err(exit_code) => return exit_code,
+    };
+    let mut vm = vm::VM::init();
+    vm.set_stress_gc(stress_gc);
+    // the vm flushes once it's done, no need to write every line right away
+    vm.set_output(Box::new(BufWriter::new(std::io::stdout())));
+    let result = if bytecode::is_bytecode(&contents) {
+        vm.interpret_bytecode(&contents)
+    } else {
+        match String::from_utf8(contents) {
+            Ok(source) => vm.interpret(source),
+            Err(_) => {
+                eprintln!("Could not read file {:?}: not valid utf-8", path);
+                return EXIT_IO_ERROR;
+            }
+        }
+    };
+    report_errors(&result);
+    match result {
+        InterpretResult::Ok => 0,
+        InterpretResult::CompileError(_) => EXIT_COMPILE_ERROR,
+        InterpretResult::RuntimeError(_) => EXIT_RUNTIME_ERROR,
     }
+}
+
+// compiles the script at `path` or reads it if it's already bytecode. The
+// strings of the function belong to the heap of `vm`.
+fn load_file(vm: &mut vm::VM, path: &PathBuf) -> Result<Function, i32> {
+    let contents = read_file(path)?;
+    let result = if bytecode::is_bytecode(&contents) {
+        vm.load_bytecode(&contents).map_err(|error| vec![error])
+    } else {
+        match String::from_utf8(contents) {
+            Ok(source) => vm.compile(source),
+            Err(_) => {
+                eprintln!("Could not read file {:?}: not valid utf-8", path);
+                return Err(EXIT_IO_ERROR);
+            }
+        }
+    };
+    result.map_err(|errors| {
+        report_errors(&InterpretResult::CompileError(errors));
+        EXIT_COMPILE_ERROR
+    })
+}
 
-    fn prompt(&mut self, name: &str) {
-        let mut line = String::new();
-        print!("{}", name);
-        std::io::stdout().flush().unwrap();
-        std::io::stdin()
-            .read_line(&mut line)
-            .expect("Error: could not read input");
-        self.vm.interpret(line.to_string());
+fn compile_file(path: PathBuf, output: Option<PathBuf>) -> i32 {
+    let mut vm = vm::VM::init();
+    let function = match load_file(&mut vm, &path) {
+        Ok(function) => function,
+        Err(exit_code) => return exit_code,
+    };
+    let output = output.unwrap_or_else(|| path.with_extension("loxc"));
+    if let Err(error) = fs::write(&output, bytecode::write(&function)) {
+        eprintln!("Could not write file {:?}: {}", output, error);
+        return EXIT_IO_ERROR;
     }
+    0
 }
 
-fn repl() {
+fn disassemble_file(path: PathBuf, json: bool) -> i32 {
     let mut vm = vm::VM::init();
-    let mut repl = Repl::init(&mut vm);
-    loop {
-        repl.prompt("> ");
+    let function = match load_file(&mut vm, &path) {
+        Ok(function) => function,
+        Err(exit_code) => return exit_code,
+    };
+    let listing = if json {
+        disassembler::function_json(&function)
+    } else {
+        disassembler::function_listing(&function)
+    };
+    match writeln!(std::io::stdout().lock(), "{}", listing) {
+        Ok(()) => 0,
+        // the reader went away, like `head` does, nothing left to do
+        Err(error) if error.kind() == std::io::ErrorKind::BrokenPipe => 0,
+        Err(error) => {
+            eprintln!("Could not write the listing: {}", error);
+            EXIT_IO_ERROR
+        }
+    }
+}
+
+fn report_errors(result: &InterpretResult) {
+    match result {
+        InterpretResult::Ok => (),
+        InterpretResult::CompileError(errors) => {
+            for error in errors.iter() {
+                eprintln!("{}", error);
+            }
+        }
+        InterpretResult::RuntimeError(error) => eprintln!("{}", error),
+    }
+}
+
+fn repl(stress_gc: bool) -> i32 {
+    match repl::Repl::init(stress_gc) {
+        Ok(mut repl) => {
+            repl.run();
+            0
+        }
+        Err(error) => {
+            eprintln!("Could not start the repl: {}", error);
+            EXIT_IO_ERROR
+        }
     }
 }
 
 fn main() {
     env::set_var("RUST_BACKTRACE", "full");
-    // let args = Cli::parse();
-    // if args.path.as_os_str().is_empty() {
-    //     repl();
-    // } else {
-    metrics::record("Total time".to_string(), || {
-        run_file(PathBuf::from("first.lox"))
-    });    
-    //       metrics::display();
-    //}
+    let args = Cli::parse();
+    if let Err(error) = configure_tracing(&args) {
+        eprintln!("Could not open trace file: {}", error);
+        std::process::exit(EXIT_IO_ERROR);
+    }
+    match args.command {
+        Some(Command::Compile { path, output }) => std::process::exit(compile_file(path, output)),
+        Some(Command::Disasm { path, json }) => std::process::exit(disassemble_file(path, json)),
+        None => (),
+    }
+    if args.path.as_os_str().is_empty() {
+        std::process::exit(repl(args.stress_gc));
+    } else {
+        let exit_code = metrics::record("Total time".to_string(), || {
+            run_file(args.path.clone(), args.stress_gc)
+        });
+        //       metrics::display();
+        std::process::exit(exit_code);
+    }
 }
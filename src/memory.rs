@@ -1,6 +1,13 @@
+use crate::chunk::Location;
+use crate::common::{
+    BoundMethod, Class, Closure, FatPointer, Function, Instance, List, Map, Obj, UpValueCell,
+    Value,
+};
+use crate::hash_map::Table;
+use crate::hasher;
 use std::alloc::{alloc, dealloc, Layout};
-use std::fmt::Debug;
-use std::mem;
+use std::collections::HashSet;
+use std::mem::size_of;
 
 pub fn allocate<T>() -> *mut u8 {
     let layout = Layout::new::<T>();
@@ -13,45 +20,46 @@ pub fn allocate<T>() -> *mut u8 {
     }
 }
 
-pub fn allocate_for_value<T>(value: T) -> *mut u8 {
-    let layout = Layout::for_value::<T>(&value);
-    println!("Layout size: {:?}", layout.size());
+// allocates a buffer for `len` bytes of string content. Empty strings still
+// get one byte since zero sized allocations aren't allowed.
+pub fn allocate_bytes(len: usize) -> *mut u8 {
+    let layout = bytes_layout(len);
     unsafe {
         let ptr = alloc(layout);
         if ptr.is_null() {
-            panic!("Unable to allocate pointer for layout {:?}", layout);
+            panic!("Unable to allocate {} bytes", len);
         }
         ptr
     }
 }
 
-pub fn add<T>(ptr: *mut u8, value: T) {
-    unsafe {
-        std::ptr::write(ptr as *mut T, value);
-    }
+// releases a buffer taken by `allocate_bytes`, `len` must be the same.
+pub fn free_bytes(ptr: *mut u8, len: usize) {
+    unsafe { dealloc(ptr, bytes_layout(len)) }
 }
 
-pub fn size_of<T>(ptr: *mut u8) -> usize {
-    unsafe { mem::size_of_val(&ptr) }
+fn bytes_layout(len: usize) -> Layout {
+    Layout::array::<u8>(len.max(1)).expect("string too large to allocate")
 }
 
-pub fn eq(ptr: *mut u8, other_ptr: *mut u8) -> bool {
-    unsafe { std::ptr::eq(ptr, other_ptr) }
+// allocates space for an object of type T and moves value into it.
+pub fn allocate_obj<T>(value: T) -> *mut T {
+    let ptr = allocate::<T>();
+    add(ptr, value);
+    ptr as *mut T
 }
 
-pub fn print<T>(ptr: *mut u8)
-where
-    T: Debug,
-{
+// drops the object and releases memory taken by `allocate_obj`.
+pub fn free<T>(ptr: *mut T) {
     unsafe {
-        println!("{:?}", *ptr);
+        std::ptr::drop_in_place(ptr);
+        dealloc(ptr as *mut u8, Layout::new::<T>());
     }
 }
 
-pub fn drop<T>(ptr: *mut u8) {
-    let layout = Layout::new::<T>();
+pub fn add<T>(ptr: *mut u8, value: T) {
     unsafe {
-        dealloc(ptr, layout);
+        std::ptr::write(ptr as *mut T, value);
     }
 }
 
@@ -59,7 +67,7 @@ pub fn read_string(ptr: *mut u8, len: usize) -> String {
     unsafe {
         let mut bytes: Vec<u8> = Vec::new();
         for i in 0..len {
-            let b = *(ptr.offset(i as isize));
+            let b = *(ptr.add(i));
             bytes.push(b);
         }
         match String::from_utf8(bytes) {
@@ -69,10 +77,376 @@ pub fn read_string(ptr: *mut u8, len: usize) -> String {
     }
 }
 
-pub fn get<T>(ptr: *mut T) -> T {
-    unsafe { std::ptr::read(ptr) }
+pub fn copy(src: *mut u8, dest: *mut u8, length: usize, offset: usize) {
+    unsafe { std::ptr::copy_nonoverlapping(src, dest.add(offset), length) }
 }
 
-pub fn copy(src: *mut u8, dest: *mut u8, length: usize, offset: usize) {
-    unsafe { std::ptr::copy_nonoverlapping(src, dest.offset(offset as isize), length) }
+// first collection happens once this many bytes were allocated
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+// after a collection the next one is scheduled at live bytes * this factor
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+// Every object the gc knows about.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum HeapObj {
+    Str(*mut u8, usize),
+    Function(*mut Function),
+    Closure(*mut Closure),
+    UpValue(*mut UpValueCell),
+    Class(*mut Class),
+    Instance(*mut Instance),
+    BoundMethod(*mut BoundMethod),
+    List(*mut List),
+    Map(*mut Map),
+}
+
+impl HeapObj {
+    fn address(&self) -> usize {
+        match self {
+            HeapObj::Str(ptr, _) => *ptr as usize,
+            HeapObj::Function(ptr) => *ptr as usize,
+            HeapObj::Closure(ptr) => *ptr as usize,
+            HeapObj::UpValue(ptr) => *ptr as usize,
+            HeapObj::Class(ptr) => *ptr as usize,
+            HeapObj::Instance(ptr) => *ptr as usize,
+            HeapObj::BoundMethod(ptr) => *ptr as usize,
+            HeapObj::List(ptr) => *ptr as usize,
+            HeapObj::Map(ptr) => *ptr as usize,
+        }
+    }
+
+    // bytes the object takes right now, storage of the collections it owns
+    // included
+    fn size(&self) -> usize {
+        match self {
+            HeapObj::Str(_, len) => *len,
+            HeapObj::Function(function) => {
+                let chunk = unsafe { &(**function).chunk };
+                size_of::<Function>()
+                    + chunk.code.capacity()
+                    + chunk.constants.values.capacity() * size_of::<Value>()
+                    + chunk.locations.capacity() * size_of::<(usize, Location)>()
+            }
+            HeapObj::Closure(_) => size_of::<Closure>(),
+            HeapObj::UpValue(_) => size_of::<UpValueCell>(),
+            HeapObj::Class(class) => {
+                size_of::<Class>() + unsafe { &(**class).methods }.allocated_size()
+            }
+            HeapObj::Instance(instance) => {
+                size_of::<Instance>() + unsafe { &(**instance).fields }.allocated_size()
+            }
+            HeapObj::BoundMethod(_) => size_of::<BoundMethod>(),
+            HeapObj::List(list) => {
+                size_of::<List>() + unsafe { &(**list).items }.capacity() * size_of::<Value>()
+            }
+            HeapObj::Map(map) => size_of::<Map>() + unsafe { &(**map).entries }.allocated_size(),
+        }
+    }
+
+    fn free(self) {
+        match self {
+            HeapObj::Str(ptr, len) => free_bytes(ptr, len),
+            HeapObj::Function(ptr) => free(ptr),
+            HeapObj::Closure(ptr) => free(ptr),
+            HeapObj::UpValue(ptr) => free(ptr),
+            HeapObj::Class(ptr) => free(ptr),
+            HeapObj::Instance(ptr) => free(ptr),
+            HeapObj::BoundMethod(ptr) => free(ptr),
+            HeapObj::List(ptr) => free(ptr),
+            HeapObj::Map(ptr) => free(ptr),
+        }
+    }
+}
+
+// Tracks all allocated objects and frees the unreachable ones with a
+// tri-color mark and sweep. It's also the only place strings are created,
+// they are interned so equal strings always share the same pointer.
+// Objects in `marked` but still in `gray` are gray, marked and traced ones
+// are black and everything else is white.
+#[derive(Debug)]
+pub(crate) struct Heap {
+    objects: Vec<HeapObj>,
+    // interned strings, entries are weak and dropped when the string is swept
+    strings: Table<Value>,
+    marked: HashSet<usize>,
+    gray: Vec<HeapObj>,
+    pub(crate) bytes_allocated: usize,
+    next_gc: usize,
+    // collect before every allocation, shakes out missing roots
+    pub(crate) stress: bool,
+}
+
+impl Heap {
+    pub(crate) fn init() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            strings: Table::init(16),
+            marked: HashSet::new(),
+            gray: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            stress: false,
+        }
+    }
+
+    pub(crate) fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub(crate) fn allocate<T>(&mut self, value: T, kind: fn(*mut T) -> HeapObj) -> *mut T {
+        let ptr = allocate_obj(value);
+        self.track(kind(ptr));
+        ptr
+    }
+
+    pub(crate) fn intern(&mut self, value: &str) -> FatPointer {
+        if let Some(existing) = self
+            .strings
+            .find_entry_with_value(value, hasher::hash(value))
+        {
+            return existing.clone();
+        }
+        let string = FatPointer::from(Obj::from(value.to_string().as_mut_str()));
+        self.track(HeapObj::Str(string.ptr, string.size));
+        self.strings.insert(string.clone(), Value::Missing);
+        string
+    }
+
+    // Lists and tables grow in place, `change` runs on `object` and whatever
+    // storage it gained is counted like any other allocation.
+    pub(crate) fn grow<R>(&mut self, object: HeapObj, change: impl FnOnce() -> R) -> R {
+        let before = object.size();
+        let result = change();
+        self.bytes_allocated = self.bytes_allocated + object.size() - before;
+        result
+    }
+
+    fn track(&mut self, object: HeapObj) {
+        self.bytes_allocated += object.size();
+        self.objects.push(object);
+    }
+
+    pub(crate) fn mark_value(&mut self, value: &Value) {
+        if let Value::Obj(obj) = value {
+            self.mark_obj(obj);
+        }
+    }
+
+    fn mark_obj(&mut self, obj: &Obj) {
+        match obj {
+            Obj::Str(string) => self.mark_string(string),
+            Obj::Fun(function) => self.mark(HeapObj::Function(*function)),
+            Obj::Closure(closure) => self.mark(HeapObj::Closure(*closure)),
+            Obj::Class(class) => self.mark(HeapObj::Class(*class)),
+            Obj::Instance(instance) => self.mark(HeapObj::Instance(*instance)),
+            Obj::BoundMethod(bound_method) => self.mark(HeapObj::BoundMethod(*bound_method)),
+            Obj::Native(_) => (),
+            Obj::List(list) => self.mark(HeapObj::List(*list)),
+            Obj::Map(map) => self.mark(HeapObj::Map(*map)),
+        }
+    }
+
+    pub(crate) fn mark_string(&mut self, string: &FatPointer) {
+        if !string.ptr.is_null() {
+            self.mark(HeapObj::Str(string.ptr, string.size));
+        }
+    }
+
+    pub(crate) fn mark_up_value(&mut self, up_value: *mut UpValueCell) {
+        self.mark(HeapObj::UpValue(up_value));
+    }
+
+    pub(crate) fn mark_closure(&mut self, closure: *mut Closure) {
+        self.mark(HeapObj::Closure(closure));
+    }
+
+    // a function keeps its name and everything in its constant table alive,
+    // nested functions are constants too so they get marked as well.
+    fn mark_function(&mut self, function: &Function) {
+        if let Some(name) = &function.name {
+            self.mark_string(name);
+        }
+        for constant in function.chunk.constants.values.iter() {
+            self.mark_value(constant);
+        }
+    }
+
+    pub(crate) fn mark_table(&mut self, table: &Table<Value>) {
+        for (key, value) in table.iter() {
+            self.mark_string(key);
+            self.mark_value(value);
+        }
+    }
+
+    fn mark(&mut self, object: HeapObj) {
+        if self.marked.insert(object.address()) {
+            self.gray.push(object);
+        }
+    }
+
+    pub(crate) fn trace_references(&mut self) {
+        while let Some(object) = self.gray.pop() {
+            self.blacken(object);
+        }
+    }
+
+    fn blacken(&mut self, object: HeapObj) {
+        match object {
+            HeapObj::Str(..) => (),
+            HeapObj::Function(function) => self.mark_function(unsafe { &*function }),
+            HeapObj::UpValue(up_value) => {
+                if let UpValueCell::Closed(value) = unsafe { &*up_value } {
+                    self.mark_value(value);
+                }
+            }
+            HeapObj::Closure(closure) => {
+                let closure = unsafe { &*closure };
+                self.mark(HeapObj::Function(closure.function));
+                for up_value in closure.up_values.iter() {
+                    self.mark_up_value(*up_value);
+                }
+            }
+            HeapObj::Class(class) => {
+                let class = unsafe { &*class };
+                self.mark_string(&class.name);
+                self.mark_table(&class.methods);
+            }
+            HeapObj::Instance(instance) => {
+                let instance = unsafe { &*instance };
+                self.mark(HeapObj::Class(instance.class));
+                self.mark_table(&instance.fields);
+            }
+            HeapObj::BoundMethod(bound_method) => {
+                let bound_method = unsafe { &*bound_method };
+                self.mark_value(&bound_method.receiver);
+                self.mark(HeapObj::Closure(bound_method.method));
+            }
+            HeapObj::List(list) => {
+                for item in unsafe { &(*list).items }.iter() {
+                    self.mark_value(item);
+                }
+            }
+            HeapObj::Map(map) => {
+                for (key, value) in unsafe { &(*map).entries }.iter() {
+                    self.mark_value(key);
+                    self.mark_value(value);
+                }
+            }
+        }
+    }
+
+    // frees every object which was not marked and resets marks for the next cycle.
+    pub(crate) fn sweep(&mut self) {
+        let marked = std::mem::take(&mut self.marked);
+        self.strings
+            .retain(|string| marked.contains(&(string.ptr as usize)));
+        let mut freed = 0;
+        self.objects.retain(|object| {
+            if marked.contains(&object.address()) {
+                return true;
+            }
+            freed += object.size();
+            object.free();
+            false
+        });
+        self.bytes_allocated -= freed;
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for object in self.objects.drain(..) {
+            object.free();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::FunctionType;
+
+    fn is_interned(heap: &Heap, value: &str) -> bool {
+        heap.strings
+            .find_entry_with_value(value, hasher::hash(value))
+            .is_some()
+    }
+
+    #[test]
+    fn sweeps_only_unreachable_objects() {
+        let mut heap = Heap::init();
+        let name = heap.intern("Point");
+        heap.intern("garbage");
+        let class = heap.allocate(Class::new(name.clone()), HeapObj::Class);
+        heap.allocate(Instance::new(class), HeapObj::Instance);
+
+        heap.mark_value(&Value::from(Obj::Class(class)));
+        heap.trace_references();
+        heap.sweep();
+        assert_eq!(heap.objects.len(), 2);
+        assert!(is_interned(&heap, "Point"));
+        assert!(!is_interned(&heap, "garbage"));
+        assert_eq!(
+            heap.bytes_allocated,
+            "Point".len() + size_of::<Class>() + unsafe { &(*class).methods }.allocated_size()
+        );
+    }
+
+    #[test]
+    fn counts_storage_collections_grow_into() {
+        let mut heap = Heap::init();
+        let list = heap.allocate(List::new(Vec::new()), HeapObj::List);
+        assert_eq!(heap.bytes_allocated, size_of::<List>());
+
+        heap.grow(HeapObj::List(list), || {
+            unsafe { &mut *list }
+                .items
+                .extend(vec![Value::Missing; 100])
+        });
+        let capacity = unsafe { &*list }.items.capacity();
+        assert!(capacity >= 100);
+        assert_eq!(
+            heap.bytes_allocated,
+            size_of::<List>() + capacity * size_of::<Value>()
+        );
+
+        // whatever was counted is given back when the list is swept
+        heap.sweep();
+        assert_eq!(heap.bytes_allocated, 0);
+    }
+
+    #[test]
+    fn interns_equal_strings_once() {
+        let mut heap = Heap::init();
+        let first = heap.intern("lox");
+        let second = heap.intern(&String::from("lox"));
+        assert_eq!(first, second);
+        assert_eq!(heap.objects.len(), 1);
+    }
+
+    #[test]
+    fn marks_values_captured_by_closures() {
+        let mut heap = Heap::init();
+        let captured = heap.intern("captured");
+        let up_value = heap.allocate(
+            UpValueCell::Closed(Value::from(Obj::Str(captured.clone()))),
+            HeapObj::UpValue,
+        );
+        let function = heap.allocate(
+            Function::new_function(FunctionType::Closure),
+            HeapObj::Function,
+        );
+        let closure = heap.allocate(Closure::new(function, vec![up_value]), HeapObj::Closure);
+
+        heap.mark_value(&Value::from(Obj::Closure(closure)));
+        heap.trace_references();
+        heap.sweep();
+        assert_eq!(heap.objects.len(), 4);
+
+        // nothing is marked anymore, everything goes
+        heap.sweep();
+        assert_eq!(heap.objects.len(), 0);
+        assert_eq!(heap.bytes_allocated, 0);
+    }
 }
@@ -0,0 +1,149 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::common::{List, Map, Obj, Value};
+use crate::memory::{self, HeapObj};
+use crate::vm::VM;
+
+pub(crate) fn define_natives(vm: &mut VM) {
+    vm.define_native("clock", 0, clock);
+    vm.define_native("str", 1, str);
+    vm.define_native("num", 1, num);
+    vm.define_native("type_of", 1, type_of);
+    vm.define_native("len", 1, len);
+    vm.define_native("push", 2, push);
+    vm.define_native("pop", 1, pop);
+    vm.define_native("insert", 3, insert);
+    vm.define_native("remove", 2, remove);
+    vm.define_native("has", 2, has);
+    vm.define_native("keys", 1, keys);
+    vm.define_native("values", 1, values);
+    vm.define_native("delete", 2, delete);
+}
+
+// seconds since unix epoch
+fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(Value::from(now.as_secs_f64()))
+}
+
+fn str(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if args[0].is_obj_string() {
+        return Ok(args[0].clone());
+    }
+    Ok(vm.new_string(args[0].to_string()))
+}
+
+fn num(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Number(_) => Ok(args[0].clone()),
+        Value::Obj(Obj::Str(_)) => {
+            let value = args[0].to_string();
+            match value.trim().parse::<f64>() {
+                Ok(number) => Ok(Value::from(number)),
+                Err(_) => Err(format!("Can't convert '{}' to a number.", value)),
+            }
+        }
+        other => Err(format!("Can't convert {} to a number.", other)),
+    }
+}
+
+fn type_of(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let type_name = match &args[0] {
+        Value::Boolean(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::Missing => "nil",
+        Value::Obj(obj) => match obj {
+            Obj::Str(_) => "string",
+            Obj::Fun(_) | Obj::Closure(_) | Obj::BoundMethod(_) | Obj::Native(_) => "function",
+            Obj::Class(_) => "class",
+            Obj::Instance(_) => "instance",
+            Obj::List(_) => "list",
+            Obj::Map(_) => "map",
+        },
+    };
+    Ok(vm.new_string(type_name.to_string()))
+}
+
+// number of items in a list, entries in a map or chars in a string
+fn len(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Obj(Obj::List(list)) => Ok(Value::from(unsafe { &**list }.items.len() as f64)),
+        Value::Obj(Obj::Map(map)) => Ok(Value::from(unsafe { &**map }.entries.len() as f64)),
+        Value::Obj(Obj::Str(string)) => {
+            let chars = memory::read_string(string.ptr, string.size).chars().count();
+            Ok(Value::from(chars as f64))
+        }
+        other => Err(format!("Can't get the length of {}.", other)),
+    }
+}
+
+// the list natives change the list in place
+fn list<'a>(native: &str, value: &Value) -> Result<&'a mut List, String> {
+    match value {
+        Value::Obj(Obj::List(list)) => Ok(unsafe { &mut **list }),
+        other => Err(format!("{} expects a list but got {}.", native, other)),
+    }
+}
+
+fn push(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let list = list("push", &args[0])?;
+    vm.grow(HeapObj::List(list), || list.items.push(args[1].clone()));
+    Ok(Value::Missing)
+}
+
+// removes and returns the last item
+fn pop(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    list("pop", &args[0])?
+        .items
+        .pop()
+        .ok_or_else(|| "Can't pop from an empty list.".to_string())
+}
+
+// `index` can be the length of the list to add at the end
+fn insert(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let list = list("insert", &args[0])?;
+    let position = list.position(&args[1], true)?;
+    vm.grow(HeapObj::List(list), || {
+        list.items.insert(position, args[2].clone())
+    });
+    Ok(Value::Missing)
+}
+
+// removes and returns the item at `index`
+fn remove(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let list = list("remove", &args[0])?;
+    let position = list.position(&args[1], false)?;
+    Ok(list.items.remove(position))
+}
+
+fn map<'a>(native: &str, value: &Value) -> Result<&'a mut Map, String> {
+    match value {
+        Value::Obj(Obj::Map(map)) => Ok(unsafe { &mut **map }),
+        other => Err(format!("{} expects a map but got {}.", native, other)),
+    }
+}
+
+fn has(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let map = map("has", &args[0])?;
+    Ok(Value::from(map.entries.get(args[1].clone()).is_some()))
+}
+
+fn keys(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let map = map("keys", &args[0])?;
+    let keys = map.entries.iter().map(|(key, _)| key.clone()).collect();
+    Ok(vm.new_list(keys))
+}
+
+fn values(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let map = map("values", &args[0])?;
+    let values = map.entries.iter().map(|(_, value)| value.clone()).collect();
+    Ok(vm.new_list(values))
+}
+
+// true if the key was in the map
+fn delete(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let map = map("delete", &args[0])?;
+    Ok(Value::from(map.entries.delete(args[1].clone()).is_some()))
+}
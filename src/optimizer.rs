@@ -0,0 +1,448 @@
+// Peephole pass the compiler runs over every chunk it finishes. It folds
+// operators applied to constants, drops values that are pushed only to be
+// popped again and jumps to the next instruction, then lays the code out
+// again with every jump pointing where it did before. The compiler emits
+// every jump with a four byte offset, laying out picks the two byte version
+// wherever the offset fits. Constants nothing refers to any more, like the
+// operands of a folded expression, are dropped from the chunk.
+use crate::chunk::{self, Chunk, Location, Operand};
+use crate::common::{self, Obj, OpCode, Value};
+use crate::memory::{self, Heap};
+
+struct Op {
+    opcode: OpCode,
+    // the whole instruction, operands included, for jumps the offset is
+    // only written once the code is laid out
+    bytes: Vec<u8>,
+    location: Location,
+    // index of the op a jump lands on, can be one past the last op
+    target: Option<usize>,
+    // whether a jump lands on this op
+    is_target: bool,
+}
+
+pub(crate) fn optimize(chunk: &mut Chunk, heap: &mut Heap) {
+    let mut ops = match decode(chunk) {
+        Some(ops) => ops,
+        None => return,
+    };
+    let mut i = 0;
+    while i < ops.len() {
+        match rewrite(&ops, i, chunk, heap) {
+            Some((length, with)) => {
+                replace(&mut ops, i, length, with);
+                // the op before may now start a pattern, like 1 + 2 + 3
+                i = i.saturating_sub(2);
+            }
+            None => i += 1,
+        }
+    }
+    compact_constants(chunk, &mut ops);
+    encode(chunk, &mut ops);
+}
+
+// None when a jump doesn't land on an instruction, the pass leaves such code
+// alone.
+fn decode(chunk: &Chunk) -> Option<Vec<Op>> {
+    let instructions = chunk.instructions();
+    let mut index_of = vec![None; chunk.code.len() + 1];
+    for (index, instruction) in instructions.iter().enumerate() {
+        index_of[instruction.offset] = Some(index);
+    }
+    index_of[chunk.code.len()] = Some(instructions.len());
+
+    let mut ops = Vec::with_capacity(instructions.len());
+    for instruction in instructions.iter() {
+        let target = match instruction.operand {
+            Operand::Jump(target) => Some((*index_of.get(target)?)?),
+            _ => None,
+        };
+        ops.push(Op {
+            opcode: instruction.opcode?,
+            bytes: chunk.code[instruction.offset..instruction.next].to_vec(),
+            location: instruction.location,
+            target,
+            is_target: false,
+        });
+    }
+    for target in ops
+        .iter()
+        .filter_map(|op| op.target)
+        .collect::<Vec<usize>>()
+    {
+        if let Some(op) = ops.get_mut(target) {
+            op.is_target = true;
+        }
+    }
+    Some(ops)
+}
+
+// Finds a rewrite for the ops starting at `i`, the number of ops it
+// replaces and what they're replaced with. Only the first op of a pattern
+// may be a jump target, jumping into the middle of it would skip part of
+// what was folded.
+fn rewrite(
+    ops: &[Op],
+    i: usize,
+    chunk: &mut Chunk,
+    heap: &mut Heap,
+) -> Option<(usize, Option<Op>)> {
+    let is_target = |index: usize| ops.get(index).is_some_and(|op| op.is_target);
+    let opcode = |index: usize| ops.get(index).map(|op| op.opcode);
+
+    if let (Some(left), Some(right), Some(operator)) = (
+        constant(&ops[i], chunk),
+        ops.get(i + 1).and_then(|op| constant(op, chunk)),
+        opcode(i + 2),
+    ) {
+        if !is_target(i + 1) && !is_target(i + 2) {
+            if let Some(value) = fold_binary(operator, &left, &right, heap) {
+                if let Some(op) = constant_op(chunk, value, ops[i + 2].location) {
+                    return Some((3, Some(op)));
+                }
+            }
+        }
+    }
+    if let (Some(value), Some(operator)) = (constant(&ops[i], chunk), opcode(i + 1)) {
+        if !is_target(i + 1) {
+            if let Some(value) = fold_unary(operator, &value) {
+                if let Some(op) = constant_op(chunk, value, ops[i + 1].location) {
+                    return Some((2, Some(op)));
+                }
+            }
+        }
+    }
+    if is_pure_push(ops[i].opcode) && opcode(i + 1) == Some(OpCode::Pop) && !is_target(i + 1) {
+        return Some((2, None));
+    }
+    if ops[i].opcode.short_variant() == OpCode::Jump && ops[i].target == Some(i + 1) {
+        return Some((1, None));
+    }
+    None
+}
+
+// the value an op pushes when it's known before the code runs
+fn constant(op: &Op, chunk: &Chunk) -> Option<Value> {
+    match op.opcode {
+        OpCode::Nil => Some(Value::Missing),
+        OpCode::True => Some(Value::from(true)),
+        OpCode::False => Some(Value::from(false)),
+        OpCode::Constant | OpCode::ConstantLong => {
+            let index = chunk::read_operand(&op.bytes[1..], op.opcode.is_long());
+            Some(chunk.constants.get(index))
+        }
+        _ => None,
+    }
+}
+
+// Operands the vm would reject are left alone so the error still happens
+// when the code runs.
+fn fold_binary(operator: OpCode, left: &Value, right: &Value, heap: &mut Heap) -> Option<Value> {
+    match (operator, left, right) {
+        (OpCode::Equal, _, _) => Some(Value::from(left == right)),
+        (OpCode::NotEqual, _, _) => Some(Value::from(left != right)),
+        (OpCode::Add, Value::Obj(Obj::Str(l)), Value::Obj(Obj::Str(r))) => {
+            let mut joined = memory::read_string(l.ptr, l.size);
+            joined.push_str(&memory::read_string(r.ptr, r.size));
+            // interned the same way the vm would, so it compares equal to
+            // strings built at runtime
+            Some(Value::from(Obj::Str(heap.intern(&joined))))
+        }
+        (_, Value::Number(l), Value::Number(r)) => match operator {
+            OpCode::Add => Some(Value::from(l + r)),
+            OpCode::Subtract => Some(Value::from(l - r)),
+            OpCode::Multiply => Some(Value::from(l * r)),
+            OpCode::Divide => Some(Value::from(l / r)),
+            OpCode::Modulo => Some(Value::from(common::modulo(*l, *r))),
+            OpCode::FloorDivide => Some(Value::from((l / r).floor())),
+            OpCode::Power => Some(Value::from(l.powf(*r))),
+            OpCode::Greater => Some(Value::from(l > r)),
+            OpCode::GreaterEqual => Some(Value::from(l >= r)),
+            OpCode::Less => Some(Value::from(l < r)),
+            OpCode::LessEqual => Some(Value::from(l <= r)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn fold_unary(operator: OpCode, value: &Value) -> Option<Value> {
+    match (operator, value) {
+        (OpCode::Negate, Value::Number(number)) => Some(Value::from(-number)),
+        (OpCode::Not, Value::Missing) | (OpCode::Not, Value::Boolean(false)) => {
+            Some(Value::from(true))
+        }
+        (OpCode::Not, _) => Some(Value::from(false)),
+        _ => None,
+    }
+}
+
+// the op that pushes `value`, None when there is no room for another
+// constant and the expression stays as it is
+fn constant_op(chunk: &mut Chunk, value: Value, location: Location) -> Option<Op> {
+    let op = |opcode: OpCode, bytes: Vec<u8>| Op {
+        opcode,
+        bytes,
+        location,
+        target: None,
+        is_target: false,
+    };
+    Some(match value {
+        Value::Missing => op(OpCode::Nil, vec![OpCode::Nil as u8]),
+        Value::Boolean(true) => op(OpCode::True, vec![OpCode::True as u8]),
+        Value::Boolean(false) => op(OpCode::False, vec![OpCode::False as u8]),
+        value => {
+            let index = chunk.add_constant(value)?;
+            let long = index > u8::MAX as usize;
+            let opcode = if long {
+                OpCode::ConstantLong
+            } else {
+                OpCode::Constant
+            };
+            let mut bytes = vec![opcode as u8];
+            bytes.extend(chunk::operand_bytes(index, long));
+            op(opcode, bytes)
+        }
+    })
+}
+
+// Keeps only the constants some op still refers to and points the ops at
+// their new index. An index only ever gets smaller so a closure keeps the
+// width of its capture operands.
+fn compact_constants(chunk: &mut Chunk, ops: &mut [Op]) {
+    let reads_constant =
+        |op: &Op| op.opcode.short_variant() == OpCode::Closure || chunk::reads_constant(op.opcode);
+    let operand = |op: &Op| chunk::read_operand(&op.bytes[1..], op.opcode.is_long());
+
+    let mut used = vec![false; chunk.constants.values.len()];
+    for op in ops.iter().filter(|op| reads_constant(op)) {
+        used[operand(op)] = true;
+    }
+    // the ones left keep their order
+    let mut new_index = Vec::with_capacity(used.len());
+    let mut constants = Vec::new();
+    for (index, value) in chunk.constants.values.drain(..).enumerate() {
+        new_index.push(constants.len());
+        if used[index] {
+            constants.push(value);
+        }
+    }
+    chunk.constants.values = constants;
+
+    for op in ops.iter_mut().filter(|op| reads_constant(op)) {
+        let long = op.opcode.is_long();
+        let index = new_index[operand(op)];
+        if op.opcode.short_variant() == OpCode::Closure {
+            let width = chunk::operand_width(long);
+            op.bytes
+                .splice(1..1 + width, chunk::operand_bytes(index, long));
+        } else {
+            let long = index > u8::MAX as usize;
+            op.opcode = if long {
+                op.opcode.long_variant()
+            } else {
+                op.opcode.short_variant()
+            };
+            op.bytes = vec![op.opcode as u8];
+            op.bytes.extend(chunk::operand_bytes(index, long));
+        }
+    }
+}
+
+// ops that only push a value and can't fail
+fn is_pure_push(opcode: OpCode) -> bool {
+    matches!(
+        opcode,
+        OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocalVariable
+            | OpCode::GetLocalVariableLong
+            | OpCode::GetUpValue
+            | OpCode::GetUpValueLong
+    )
+}
+
+// replaces `length` ops at `start`, jumps that landed on `start` land on
+// whatever takes its place.
+fn replace(ops: &mut Vec<Op>, start: usize, length: usize, with: Option<Op>) {
+    let removed = length - with.is_some() as usize;
+    let is_target = ops[start].is_target;
+    ops.splice(start..start + length, with);
+    if let Some(op) = ops.get_mut(start) {
+        op.is_target |= is_target;
+    }
+    for op in ops.iter_mut() {
+        if let Some(target) = op.target.as_mut() {
+            if *target >= start + length {
+                *target -= removed;
+            } else if *target > start {
+                *target = start;
+            }
+        }
+    }
+}
+
+// Starts with every jump long and shortens the ones whose offset fits in two
+// bytes until none is left. Shortening a jump never makes another one
+// longer, so a jump that fits once keeps fitting.
+fn encode(chunk: &mut Chunk, ops: &mut [Op]) {
+    for op in ops.iter_mut().filter(|op| op.target.is_some()) {
+        op.opcode = op.opcode.long_variant();
+    }
+    loop {
+        let offsets = offsets(ops);
+        let mut shortened = false;
+        for (i, op) in ops.iter_mut().enumerate() {
+            if op.target.is_some()
+                && op.opcode != op.opcode.short_variant()
+                && jump(op, &offsets, i) <= u16::MAX as usize
+            {
+                op.opcode = op.opcode.short_variant();
+                shortened = true;
+            }
+        }
+        if !shortened {
+            break;
+        }
+    }
+
+    let offsets = offsets(ops);
+    chunk.code.clear();
+    chunk.locations.clear();
+    for (i, op) in ops.iter().enumerate() {
+        let bytes = match op.target {
+            Some(_) => {
+                let jump = jump(op, &offsets, i);
+                let width = size(op) - 1;
+                let mut bytes = vec![op.opcode as u8];
+                // most significant byte first
+                for byte in (0..width).rev() {
+                    bytes.push(((jump >> (byte * 8)) & 0xff) as u8);
+                }
+                bytes
+            }
+            None => op.bytes.clone(),
+        };
+        for byte in bytes {
+            chunk.write_chunk(byte, op.location);
+        }
+    }
+}
+
+fn size(op: &Op) -> usize {
+    match op.opcode {
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => 3,
+        OpCode::JumpLong | OpCode::JumpIfFalseLong | OpCode::LoopLong => 5,
+        _ => op.bytes.len(),
+    }
+}
+
+// where every op starts, and where the code ends
+fn offsets(ops: &[Op]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(ops.len() + 1);
+    let mut offset = 0;
+    for op in ops.iter() {
+        offsets.push(offset);
+        offset += size(op);
+    }
+    offsets.push(offset);
+    offsets
+}
+
+// offset of the jump at `i`, relative to the end of the instruction
+fn jump(op: &Op, offsets: &[usize], i: usize) -> usize {
+    let target = offsets[op.target.unwrap()];
+    let after = offsets[i] + size(op);
+    if op.opcode.short_variant() == OpCode::Loop {
+        after - target
+    } else {
+        target - after
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Function;
+    use crate::compiler::Compiler;
+    use crate::disassembler;
+    use crate::scanner::Scanner;
+
+    fn listing(source: &str) -> String {
+        let mut heap = Heap::init();
+        let scanner = Scanner::init(0, 0, vec![]);
+        let function = Compiler::init(scanner, &mut heap).compile(source.to_string());
+        disassembler::listing(&Into::<Function>::into(function.unwrap()).chunk, "<script>")
+    }
+
+    #[test]
+    fn folds_constant_expressions() {
+        assert_eq!(
+            listing("print 1 + 2 * 3;\nprint \"a\" + \"b\" + \"c\";\nprint !(1 >= 2) == !nil;\nprint a >= 1 != true;"),
+            "\
+== <script> ==
+0000    1 OP_CONSTANT                  2 '7'
+0002    | OP_PRINT
+0003    2 OP_CONSTANT                  3 'abc'
+0005    | OP_PRINT
+0006    3 OP_TRUE
+0007    | OP_PRINT
+0008    4 OP_GET_GLOBAL_VARIABLE       0 'a'
+0010    | OP_CONSTANT                  1 '1'
+0012    | OP_GREATER_EQUAL
+0013    | OP_TRUE
+0014    | OP_NOT_EQUAL
+0015    | OP_PRINT
+0016    | OP_NIL
+0017    | OP_RETURN"
+        );
+    }
+
+    #[test]
+    fn drops_constants_that_were_folded_away() {
+        // 1 and 2 are gone, the sum was added after the closure
+        assert_eq!(
+            listing("print 1 + 2;\nfun f() {}"),
+            "\
+== <script> ==
+0000    1 OP_CONSTANT                  2 '3'
+0002    | OP_PRINT
+0003    2 OP_CLOSURE                   1 <fn f>
+0005    | OP_DEFINE_GLOBAL_VARIABLE    0 'f'
+0007    | OP_NIL
+0008    | OP_RETURN"
+        );
+    }
+
+    #[test]
+    fn keeps_jumps_pointing_at_the_same_code() {
+        // the unused values go away, which moves the loop body and the
+        // code after it
+        assert_eq!(
+            listing("var i = 0;\nwhile (i < 2 + 1) {\n  1;\n  i = i + 1;\n}\nprint i;"),
+            "\
+== <script> ==
+0000    1 OP_CONSTANT                  1 '0'
+0002    | OP_DEFINE_GLOBAL_VARIABLE    0 'i'
+0004    2 OP_GET_GLOBAL_VARIABLE       2 'i'
+0006    | OP_CONSTANT                  7 '3'
+0008    | OP_LESS
+0009    | OP_JUMP_IF_FALSE             9 -> 24
+0012    | OP_POP
+0013    4 OP_GET_GLOBAL_VARIABLE       4 'i'
+0015    | OP_CONSTANT                  5 '1'
+0017    | OP_ADD
+0018    | OP_SET_GLOBAL_VARIABLE       3 'i'
+0020    | OP_POP
+0021    5 OP_LOOP                     21 -> 4
+0024    | OP_POP
+0025    6 OP_GET_GLOBAL_VARIABLE       6 'i'
+0027    | OP_PRINT
+0028    | OP_NIL
+0029    | OP_RETURN"
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use crate::scanner::{Scanner, TokenType};
+use crate::vm::VM;
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use std::env;
+use std::path::PathBuf;
+
+const PROMPT: &str = "> ";
+// shown while an entry spans more than one line
+const CONTINUATION_PROMPT: &str = ". ";
+const HELP: &str = "\
+:quit           leave the repl, so does ctrl-d
+:globals        list global variables and their values
+:disasm <name>  disassemble the function or class stored in global <name>
+:help           show this message";
+
+// Every entry runs in the same vm so globals and interned strings are
+// still there for the entries after it.
+pub(crate) struct Repl {
+    vm: VM,
+    editor: Editor<()>,
+    history: Option<PathBuf>,
+}
+
+impl Repl {
+    pub(crate) fn init(stress_gc: bool) -> Result<Repl, ReadlineError> {
+        let mut vm = VM::init();
+        vm.set_stress_gc(stress_gc);
+        vm.set_echo(true);
+        let mut editor = Editor::<()>::new()?;
+        let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(".rlox_history"));
+        if let Some(history) = &history {
+            // there is no history the first time round
+            let _ = editor.load_history(history);
+        }
+        Ok(Repl {
+            vm,
+            editor,
+            history,
+        })
+    }
+
+    pub(crate) fn run(&mut self) {
+        while let Some(entry) = self.read_entry() {
+            if let Some(command) = entry.trim().strip_prefix(':') {
+                if !self.run_command(command) {
+                    break;
+                }
+                continue;
+            }
+            // the session goes on after errors
+            crate::report_errors(&self.vm.interpret(entry));
+        }
+        if let Some(history) = &self.history {
+            if let Err(error) = self.editor.save_history(history) {
+                eprintln!("Could not save history to {:?}: {}", history, error);
+            }
+        }
+    }
+
+    // reads lines until braces, parentheses and strings are closed, returns
+    // None once input is closed.
+    fn read_entry(&mut self) -> Option<String> {
+        let mut entry = String::new();
+        loop {
+            let prompt = if entry.is_empty() {
+                PROMPT
+            } else {
+                CONTINUATION_PROMPT
+            };
+            match self.editor.readline(prompt) {
+                Ok(line) => {
+                    if !entry.is_empty() {
+                        entry.push('\n');
+                    }
+                    entry.push_str(&line);
+                    if entry.trim().is_empty() {
+                        entry.clear();
+                    } else if !is_incomplete(&entry) {
+                        self.editor.add_history_entry(entry.as_str());
+                        return Some(entry);
+                    }
+                }
+                // ctrl-c drops whatever was typed so far
+                Err(ReadlineError::Interrupted) => entry.clear(),
+                Err(ReadlineError::Eof) => return None,
+                Err(error) => {
+                    eprintln!("Could not read input: {}", error);
+                    return None;
+                }
+            }
+        }
+    }
+
+    // returns false when the repl should stop.
+    fn run_command(&mut self, command: &str) -> bool {
+        let mut words = command.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("quit"), None) => return false,
+            (Some("globals"), None) => {
+                for (name, value) in self.vm.globals() {
+                    println!("{} = {}", name, value);
+                }
+            }
+            (Some("disasm"), Some(name)) => match self.vm.disassemble(name) {
+                Ok(listings) => listings.iter().for_each(|listing| println!("{}", listing)),
+                Err(error) => eprintln!("{}", error),
+            },
+            (Some("help"), None) => println!("{}", HELP),
+            _ => eprintln!("Unknown command ':{}', try :help.", command),
+        }
+        true
+    }
+}
+
+// Whether more lines are needed before `source` can be compiled. Anything
+// else, including code that won't compile, is handed to the compiler so it
+// can report the errors.
+fn is_incomplete(source: &str) -> bool {
+    let chars: Vec<char> = source.chars().collect();
+    let mut scanner = Scanner::init(0, chars.len(), chars.clone());
+    let mut depth = 0;
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::LeftBrace | TokenType::LeftParen | TokenType::LeftBracket => depth += 1,
+            TokenType::RightBrace | TokenType::RightParen | TokenType::RightBracket => depth -= 1,
+            // the only error starting at a quote is an unterminated string
+            TokenType::Error if chars[token.start] == '"' => return true,
+            TokenType::Eof => return depth > 0,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_blocks_and_strings_to_close() {
+        assert!(is_incomplete("fun f() {"));
+        assert!(is_incomplete("print (1 +"));
+        assert!(is_incomplete("var xs = [1,"));
+        assert!(is_incomplete("var s = \"two\nlines"));
+        assert!(!is_incomplete("fun f() {\n  print 1;\n}"));
+        assert!(!is_incomplete("print \"{\";"));
+        assert!(!is_incomplete("// {"));
+        // too many closing braces is a compile error, not more input
+        assert!(!is_incomplete("}"));
+        assert!(!is_incomplete("print [[1], [2]];"));
+    }
+}
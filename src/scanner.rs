@@ -1,3 +1,4 @@
+use crate::chunk::Location;
 use num_derive::FromPrimitive;
 use std::cmp::Ordering;
 
@@ -47,6 +48,13 @@ pub(crate) enum TokenType {
     While = 38,
     Error = 39,
     Eof = 40,
+    LeftBracket = 41,
+    RightBracket = 42,
+    Colon = 43,
+    Break = 44,
+    Continue = 45,
+    Percent = 46,
+    StarStar = 48,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -54,7 +62,20 @@ pub(crate) struct Token {
     pub token_type: TokenType,
     pub start: usize,
     pub length: usize,
+    // where the token starts, columns count chars from 1
     pub line: u32,
+    pub column: u32,
+    // what went wrong, only error tokens have one
+    pub message: Option<&'static str>,
+}
+
+impl Token {
+    pub(crate) const fn location(&self) -> Location {
+        Location {
+            line: self.line,
+            column: self.column,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -62,6 +83,11 @@ pub(crate) struct Scanner {
     start: usize,
     current: usize,
     line: u32,
+    // index of the first char on the current line
+    line_start: usize,
+    // line and column of `start`, a string can end on a later line
+    start_line: u32,
+    start_column: u32,
     chars: Vec<char>,
     total_size: usize,
 }
@@ -76,6 +102,9 @@ impl Scanner {
             start,
             current: start,
             line: 1,
+            line_start: start,
+            start_line: 1,
+            start_column: 1,
             total_size,
             chars: source,
         }
@@ -87,12 +116,15 @@ impl Scanner {
         self.total_size = total_size;
         self.current = start;
         self.line = 1;
+        self.line_start = start;
         self.start = start
     }
 
     pub(crate) fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = (self.start - self.line_start + 1) as u32;
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
         }
@@ -102,7 +134,7 @@ impl Scanner {
 
         // -1 because we want to look at the consumed char
         // look for number token
-        if self.chars[self.start].is_digit(10) {
+        if self.chars[self.start].is_ascii_digit() {
             self.number_token();
             return self.make_token(TokenType::Number);
         }
@@ -121,13 +153,26 @@ impl Scanner {
             ')' => self.make_token(TokenType::RightParen),
             '{' => self.make_token(TokenType::LeftBrace),
             '}' => self.make_token(TokenType::RightBrace),
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
             ';' => self.make_token(TokenType::Semicolon),
+            ':' => self.make_token(TokenType::Colon),
             ',' => self.make_token(TokenType::Comma),
             '.' => self.make_token(TokenType::Dot),
             '-' => self.make_token(TokenType::Minus),
             '+' => self.make_token(TokenType::Plus),
+            '%' => self.make_token(TokenType::Percent),
+            // `//` always starts a comment, so floor division has no
+            // operator until it gets a spelling comments can't collide with
             '/' => self.make_token(TokenType::Slash),
-            '*' => self.make_token(TokenType::Star),
+            '*' => {
+                let token_type = if self.match_char('*') {
+                    TokenType::StarStar
+                } else {
+                    TokenType::Star
+                };
+                self.make_token(token_type)
+            }
             '!' => {
                 let token_type = if self.match_char('=') {
                     TokenType::BangEqual
@@ -164,10 +209,10 @@ impl Scanner {
             '"' => {
                 // we support multi line string
                 while self.peek() != '"' && !self.is_at_end() {
-                    if self.peek() == '\n' {
-                        self.line += 1;
-                    }
                     self.advance();
+                    if self.chars[self.current - 1] == '\n' {
+                        self.new_line();
+                    }
                 }
 
                 // just checking if previous while loop broke due to
@@ -184,21 +229,21 @@ impl Scanner {
     }
 
     fn number_token(&mut self) {
-        while self.peek().is_digit(10) {
+        while self.peek().is_ascii_digit() {
             self.advance();
         }
         // check for fractional part
-        if self.peek() == '.' && self.peek_next().is_digit(10) {
+        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
             self.advance();
 
-            while self.peek().is_digit(10) {
+            while self.peek().is_ascii_digit() {
                 self.advance();
             }
         }
     }
 
     fn identifier(&mut self) {
-        while self.peek().is_digit(10) || is_alpha(self.peek()) {
+        while self.peek().is_ascii_digit() || is_alpha(self.peek()) {
             self.advance();
         }
     }
@@ -212,16 +257,20 @@ impl Scanner {
             token_type,
             start: self.start,
             length: (self.current - self.start),
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            message: None,
         }
     }
 
-    const fn error_token(&self, message: &str) -> Token {
+    const fn error_token(&self, message: &'static str) -> Token {
         Token {
             token_type: TokenType::Error,
             start: self.start,
-            length: message.len(),
-            line: self.line,
+            length: (self.current - self.start),
+            line: self.start_line,
+            column: self.start_column,
+            message: Some(message),
         }
     }
 
@@ -229,6 +278,12 @@ impl Scanner {
         self.current += 1;
     }
 
+    // called after consuming a newline
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn skip_whitespace(&mut self) {
         loop {
             match self.peek() {
@@ -236,17 +291,16 @@ impl Scanner {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.new_line();
                 }
-                '/' => {
-                    // handle comments
-                    if self.peek_next() == '/' {
-                        // we have single line comment so once we see
-                        // next line or end of file we stop.
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
+                // handle comments, a lone slash is division so it falls
+                // through to the return below
+                '/' if self.peek_next() == '/' => {
+                    // we have single line comment so once we see
+                    // next line or end of file we stop.
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => {
@@ -273,7 +327,19 @@ impl Scanner {
     fn identifier_type(&mut self) -> TokenType {
         match self.chars[self.start] {
             'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-            'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
+            'b' => self.check_keyword(1, 4, "reak", TokenType::Break),
+            'c' => {
+                if self.current - self.start > 1 {
+                    // looking for next char
+                    match self.chars[self.start + 1] {
+                        'l' => self.check_keyword(2, 3, "ass", TokenType::Class),
+                        'o' => self.check_keyword(2, 6, "ntinue", TokenType::Continue),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
             'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
             'i' => self.check_keyword(1, 1, "f", TokenType::If),
             'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
@@ -286,12 +352,12 @@ impl Scanner {
             'f' => {
                 if self.current - self.start > 1 {
                     // looking for next char
-                    return match self.chars[self.start + 1] {
+                    match self.chars[self.start + 1] {
                         'a' => self.check_keyword(2, 3, "lse", TokenType::False),
                         'o' => self.check_keyword(2, 1, "r", TokenType::For),
                         'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
                         _ => TokenType::Identifier,
-                    };
+                    }
                 } else {
                     TokenType::Identifier
                 }
@@ -299,11 +365,11 @@ impl Scanner {
             't' => {
                 if self.current - self.start > 1 {
                     // looking for next char
-                    return match self.chars[self.start + 1] {
+                    match self.chars[self.start + 1] {
                         'h' => self.check_keyword(2, 2, "is", TokenType::This),
                         'r' => self.check_keyword(2, 2, "ue", TokenType::True),
                         _ => TokenType::Identifier,
-                    };
+                    }
                 } else {
                     TokenType::Identifier
                 }
@@ -322,7 +388,8 @@ impl Scanner {
         let start_index = self.start + start;
         let end_index_exclusive = start_index + length;
 
-        if self.chars.len() >= end_index_exclusive {
+        // identifier must be exactly as long as the keyword, `classy` is not `class`
+        if self.current == end_index_exclusive {
             let slice = &self.chars[start_index..end_index_exclusive];
             let rest_slice: Vec<char> = rest.chars().collect();
             let o = slice.cmp(&rest_slice);
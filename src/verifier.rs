@@ -0,0 +1,511 @@
+// Checks code the compiler didn't produce, like a loaded .loxc file, before
+// the vm runs it. The vm itself trusts the code, a bad operand or jump would
+// make it read past the chunk or index the stack out of bounds.
+use crate::chunk;
+use crate::common::{Function, FunctionType, Obj, OpCode, Value};
+
+// The vm starts the script without arguments and without a closure around
+// it, so it can't have parameters or captured variables.
+pub(crate) fn verify(script: &Function) -> Result<(), String> {
+    let problem = if !matches!(script.func_type, FunctionType::Script) {
+        Some("the top level function isn't a script")
+    } else if script.arity != 0 {
+        Some("the script can't have parameters")
+    } else if script.up_value_count != 0 {
+        Some("the script can't capture variables")
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        return Err(format!("Invalid bytecode in {}: {}.", script, problem));
+    }
+    verify_function(script)
+}
+
+fn verify_function(function: &Function) -> Result<(), String> {
+    let verifier = Verifier { function };
+    let instructions = verifier.decode()?;
+    verifier.check_stack(&instructions)?;
+    // nested functions are verified on their own
+    for constant in function.chunk.constants.values.iter() {
+        if let Value::Obj(Obj::Fun(nested)) = constant {
+            verify_function(unsafe { &**nested })?;
+        }
+    }
+    Ok(())
+}
+
+struct Instruction {
+    offset: usize,
+    opcode: OpCode,
+    // offset of the next instruction
+    next: usize,
+}
+
+struct Verifier<'a> {
+    function: &'a Function,
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&self, offset: usize, message: String) -> String {
+        format!(
+            "Invalid bytecode in {} at offset {}: {}.",
+            self.function, offset, message
+        )
+    }
+
+    fn code(&self) -> &[u8] {
+        &self.function.chunk.code
+    }
+
+    fn byte(&self, offset: usize) -> usize {
+        self.code()[offset] as usize
+    }
+
+    // index operand at `offset` of an instruction with `opcode`
+    fn index(&self, offset: usize, opcode: OpCode) -> usize {
+        chunk::read_operand(&self.code()[offset..], opcode.is_long())
+    }
+
+    // walks the code once checking every opcode, that its operands are
+    // within the chunk and that constants exist and have the right type.
+    fn decode(&self) -> Result<Vec<Instruction>, String> {
+        let code = self.code();
+        let constants = &self.function.chunk.constants.values;
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < code.len() {
+            let opcode: OpCode = match num::FromPrimitive::from_u8(code[offset]) {
+                Some(opcode) => opcode,
+                None => return Err(self.error(offset, format!("unknown opcode {}", code[offset]))),
+            };
+            let long = opcode.is_long();
+            let width = chunk::operand_width(long);
+            let operands = match opcode.short_variant() {
+                OpCode::Constant
+                | OpCode::GetLocalVariable
+                | OpCode::SetLocalVariable
+                | OpCode::GetUpValue
+                | OpCode::SetUpValue
+                | OpCode::Call
+                | OpCode::DefineGlobalVariable
+                | OpCode::GetGlobalVariable
+                | OpCode::SetGlobalVariable
+                | OpCode::Class
+                | OpCode::GetProperty
+                | OpCode::SetProperty
+                | OpCode::Method
+                | OpCode::GetSuper
+                | OpCode::Closure
+                | OpCode::BuildList
+                | OpCode::BuildMap => width,
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                    if long {
+                        4
+                    } else {
+                        2
+                    }
+                }
+                _ => 0,
+            };
+            if offset + operands >= code.len() {
+                return Err(self.error(offset, format!("{:?} is missing operands", opcode)));
+            }
+
+            let mut next = offset + 1 + operands;
+            match opcode.short_variant() {
+                OpCode::Constant => {
+                    let index = self.index(offset + 1, opcode);
+                    self.constant(offset, index)?;
+                    // functions are only ever called through the closure
+                    // wrapping them
+                    if let Value::Obj(Obj::Fun(_)) = constants[index] {
+                        let message = "Constant can't load a function".to_string();
+                        return Err(self.error(offset, message));
+                    }
+                }
+                OpCode::DefineGlobalVariable
+                | OpCode::GetGlobalVariable
+                | OpCode::SetGlobalVariable
+                | OpCode::Class
+                | OpCode::GetProperty
+                | OpCode::SetProperty
+                | OpCode::Method
+                | OpCode::GetSuper => {
+                    let index = self.index(offset + 1, opcode);
+                    self.constant(offset, index)?;
+                    if !constants[index].is_obj_string() {
+                        return Err(self.error(offset, format!("{:?} needs a name", opcode)));
+                    }
+                }
+                OpCode::GetUpValue | OpCode::SetUpValue => {
+                    self.up_value(offset, self.index(offset + 1, opcode))?;
+                }
+                OpCode::Closure => {
+                    let index = self.index(offset + 1, opcode);
+                    self.constant(offset, index)?;
+                    let up_value_count = match &constants[index] {
+                        Value::Obj(Obj::Fun(function)) => unsafe { &**function }.up_value_count,
+                        _ => return Err(self.error(offset, "Closure needs a function".to_string())),
+                    };
+                    // an is_local byte and an index for every captured variable
+                    next += up_value_count * (1 + width);
+                    if next > code.len() {
+                        return Err(self.error(offset, "Closure is missing captures".to_string()));
+                    }
+                    for capture in (offset + 1 + width..next).step_by(1 + width) {
+                        match self.byte(capture) {
+                            1 => (),
+                            0 => self.up_value(offset, self.index(capture + 1, opcode))?,
+                            _ => {
+                                return Err(self.error(offset, "invalid capture".to_string()));
+                            }
+                        }
+                    }
+                }
+                _ => (),
+            }
+            instructions.push(Instruction {
+                offset,
+                opcode,
+                next,
+            });
+            offset = next;
+        }
+        Ok(instructions)
+    }
+
+    fn constant(&self, offset: usize, index: usize) -> Result<(), String> {
+        let count = self.function.chunk.constants.values.len();
+        if index >= count {
+            return Err(self.error(
+                offset,
+                format!("constant {} doesn't exist, there are {}", index, count),
+            ));
+        }
+        Ok(())
+    }
+
+    fn up_value(&self, offset: usize, index: usize) -> Result<(), String> {
+        if index >= self.function.up_value_count {
+            return Err(self.error(
+                offset,
+                format!(
+                    "upvalue {} doesn't exist, there are {}",
+                    index, self.function.up_value_count
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // Follows every path through the code tracking how many values are on
+    // the stack. Every jump has to land on an instruction, paths meeting at
+    // an instruction have to agree on the depth, nothing may pop or read a
+    // slot below the frame, and no path may run past the end of the code.
+    fn check_stack(&self, instructions: &[Instruction]) -> Result<(), String> {
+        let code = self.code();
+        if instructions.is_empty() {
+            return Err(self.error(0, "execution runs past the end".to_string()));
+        }
+        // instruction at each offset, None for bytes in the middle of one
+        let mut at_offset: Vec<Option<usize>> = vec![None; code.len()];
+        for (index, instruction) in instructions.iter().enumerate() {
+            at_offset[instruction.offset] = Some(index);
+        }
+        let mut depths: Vec<Option<usize>> = vec![None; instructions.len()];
+        // slot zero holds the function or receiver, then come the arguments
+        let mut pending = vec![(0, self.function.arity as usize + 1)];
+
+        while let Some((index, depth)) = pending.pop() {
+            match depths[index] {
+                Some(existing) if existing == depth => continue,
+                Some(existing) => {
+                    return Err(self.error(
+                        instructions[index].offset,
+                        format!(
+                            "stack depth is {} on one path and {} on another",
+                            existing, depth
+                        ),
+                    ));
+                }
+                None => depths[index] = Some(depth),
+            }
+
+            let instruction = &instructions[index];
+            let offset = instruction.offset;
+            let (needs, pushes) = self.stack_effect(instruction);
+            if depth < needs {
+                return Err(self.error(
+                    offset,
+                    format!(
+                        "{:?} needs {} values but the stack has {}",
+                        instruction.opcode, needs, depth
+                    ),
+                ));
+            }
+            let depth = depth - needs + pushes;
+            let opcode = instruction.opcode;
+            match opcode.short_variant() {
+                OpCode::GetLocalVariable | OpCode::SetLocalVariable => {
+                    self.local(offset, self.index(offset + 1, opcode), depth)?;
+                }
+                OpCode::Closure => {
+                    let width = chunk::operand_width(opcode.is_long());
+                    for capture in (offset + 1 + width..instruction.next).step_by(1 + width) {
+                        if self.byte(capture) == 1 {
+                            self.local(offset, self.index(capture + 1, opcode), depth)?;
+                        }
+                    }
+                }
+                _ => (),
+            }
+
+            let mut successors = Vec::new();
+            match opcode.short_variant() {
+                OpCode::Return => (),
+                OpCode::Jump | OpCode::Loop => successors.push(self.jump_target(instruction)?),
+                OpCode::JumpIfFalse => {
+                    successors.push(self.jump_target(instruction)?);
+                    successors.push(instruction.next);
+                }
+                _ => successors.push(instruction.next),
+            }
+            for target in successors {
+                match at_offset.get(target) {
+                    Some(Some(target)) => pending.push((*target, depth)),
+                    Some(None) => {
+                        return Err(self.error(
+                            offset,
+                            format!("jump target {} is inside an instruction", target),
+                        ));
+                    }
+                    None => {
+                        return Err(self.error(offset, "execution runs past the end".to_string()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // same calculation as the vm, the jump is relative to the end of the operands
+    fn jump_target(&self, instruction: &Instruction) -> Result<usize, String> {
+        let width = instruction.next - instruction.offset - 1;
+        let jump = self.function.chunk.get_offset(instruction.offset, width);
+        match instruction.opcode {
+            OpCode::Loop | OpCode::LoopLong => instruction.next.checked_sub(jump).ok_or_else(|| {
+                self.error(
+                    instruction.offset,
+                    "loop jumps before the start".to_string(),
+                )
+            }),
+            _ => Ok(instruction.next + jump),
+        }
+    }
+
+    fn local(&self, offset: usize, slot: usize, depth: usize) -> Result<(), String> {
+        if slot >= depth {
+            return Err(self.error(
+                offset,
+                format!(
+                    "local {} doesn't exist, the stack has {} values",
+                    slot, depth
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // how many values an instruction takes off the stack and how many it leaves
+    fn stack_effect(&self, instruction: &Instruction) -> (usize, usize) {
+        match instruction.opcode {
+            OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetGlobalVariable
+            | OpCode::GetGlobalVariableLong
+            | OpCode::GetLocalVariable
+            | OpCode::GetLocalVariableLong
+            | OpCode::GetUpValue
+            | OpCode::GetUpValueLong
+            | OpCode::Closure
+            | OpCode::ClosureLong
+            | OpCode::Class
+            | OpCode::ClassLong => (0, 1),
+            OpCode::Negate
+            | OpCode::Not
+            | OpCode::SetGlobalVariable
+            | OpCode::SetGlobalVariableLong
+            | OpCode::SetLocalVariable
+            | OpCode::SetLocalVariableLong
+            | OpCode::SetUpValue
+            | OpCode::SetUpValueLong
+            | OpCode::GetProperty
+            | OpCode::GetPropertyLong
+            | OpCode::JumpIfFalse
+            | OpCode::JumpIfFalseLong => (1, 1),
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::FloorDivide
+            | OpCode::Power
+            | OpCode::Equal
+            | OpCode::NotEqual
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::SetProperty
+            | OpCode::SetPropertyLong
+            | OpCode::GetSuper
+            | OpCode::GetSuperLong
+            | OpCode::GetIndex => (2, 1),
+            OpCode::SetIndex => (3, 1),
+            OpCode::Method | OpCode::MethodLong | OpCode::Inherit => (2, 1),
+            OpCode::Print
+            | OpCode::Pop
+            | OpCode::DefineGlobalVariable
+            | OpCode::DefineGlobalVariableLong
+            | OpCode::CloseUpValue
+            | OpCode::Return => (1, 0),
+            OpCode::Jump | OpCode::JumpLong | OpCode::Loop | OpCode::LoopLong => (0, 0),
+            // the callee and its arguments are replaced by the result
+            OpCode::Call => (self.byte(instruction.offset + 1) + 1, 1),
+            // the items are replaced by the list
+            OpCode::BuildList | OpCode::BuildListLong => {
+                (self.index(instruction.offset + 1, instruction.opcode), 1)
+            }
+            OpCode::BuildMap | OpCode::BuildMapLong => {
+                (2 * self.index(instruction.offset + 1, instruction.opcode), 1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{Chunk, Location};
+    use crate::common::FunctionType;
+    use crate::compiler::Compiler;
+    use crate::memory::{Heap, HeapObj};
+    use crate::scanner::Scanner;
+
+    fn script(code: Vec<u8>, constants: Vec<Value>) -> Function {
+        let mut function = Function::new_function(FunctionType::Script);
+        function.chunk = Chunk::init();
+        for byte in code {
+            function.chunk.write_chunk(byte, Location { line: 1, column: 1 });
+        }
+        for constant in constants {
+            function.chunk.add_constant(constant).unwrap();
+        }
+        function
+    }
+
+    fn error(code: Vec<u8>, constants: Vec<Value>) -> String {
+        verify(&script(code, constants)).unwrap_err()
+    }
+
+    const NIL: u8 = OpCode::Nil as u8;
+    const POP: u8 = OpCode::Pop as u8;
+    const RETURN: u8 = OpCode::Return as u8;
+    const JUMP: u8 = OpCode::Jump as u8;
+    const JUMP_IF_FALSE: u8 = OpCode::JumpIfFalse as u8;
+
+    #[test]
+    fn accepts_what_the_compiler_produces() {
+        let mut heap = Heap::init();
+        let source = "var a = 1; if (a) print a; else { var b = a; print b; } \
+                      while (a < 3) a = a + 1; \
+                      fun outer(x) { fun inner() { return x; } return inner; } \
+                      class A { init(v) { this.v = v; } } \
+                      class B < A { get() { return super.init; } } print outer(1)();";
+        let scanner = Scanner::init(0, 0, vec![]);
+        let function = Compiler::init(scanner, &mut heap)
+            .compile(source.to_string())
+            .unwrap();
+        assert_eq!(verify(&Into::<Function>::into(function)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_invalid_code() {
+        let constant = OpCode::Constant as u8;
+        assert_eq!(
+            error(vec![0], vec![]),
+            "Invalid bytecode in <script> at offset 0: unknown opcode 0."
+        );
+        assert_eq!(
+            error(vec![constant], vec![]),
+            "Invalid bytecode in <script> at offset 0: Constant is missing operands."
+        );
+        assert_eq!(
+            error(vec![constant, 1, RETURN], vec![Value::from(1.0)]),
+            "Invalid bytecode in <script> at offset 0: constant 1 doesn't exist, there are 1."
+        );
+        assert_eq!(
+            error(
+                vec![NIL, JUMP, 0, 1, constant, 0, RETURN],
+                vec![Value::from(1.0)]
+            ),
+            "Invalid bytecode in <script> at offset 1: jump target 5 is inside an instruction."
+        );
+        assert_eq!(
+            error(vec![POP, POP, NIL, RETURN], vec![]),
+            "Invalid bytecode in <script> at offset 1: Pop needs 1 values but the stack has 0."
+        );
+        assert_eq!(
+            error(vec![NIL, POP], vec![]),
+            "Invalid bytecode in <script> at offset 1: execution runs past the end."
+        );
+        // one path leaves a value on the stack the other doesn't
+        assert_eq!(
+            error(vec![NIL, JUMP_IF_FALSE, 0, 1, NIL, NIL, RETURN], vec![]),
+            "Invalid bytecode in <script> at offset 5: stack depth is 3 on one path and 2 on another."
+        );
+    }
+
+    #[test]
+    fn rejects_scripts_the_vm_cant_start() {
+        let mut with_parameter = script(vec![NIL, RETURN], vec![]);
+        with_parameter.arity = 1;
+        assert_eq!(
+            verify(&with_parameter).unwrap_err(),
+            "Invalid bytecode in <script>: the script can't have parameters."
+        );
+
+        let mut capturing = script(vec![NIL, RETURN], vec![]);
+        capturing.up_value_count = 1;
+        assert_eq!(
+            verify(&capturing).unwrap_err(),
+            "Invalid bytecode in <script>: the script can't capture variables."
+        );
+
+        let mut not_script = script(vec![NIL, RETURN], vec![]);
+        not_script.func_type = FunctionType::Closure;
+        assert!(verify(&not_script)
+            .unwrap_err()
+            .ends_with(": the top level function isn't a script."));
+    }
+
+    #[test]
+    fn rejects_functions_loaded_without_a_closure() {
+        let mut heap = Heap::init();
+        let function = heap.allocate(
+            Function::new_function(FunctionType::Closure),
+            HeapObj::Function,
+        );
+        assert_eq!(
+            error(
+                vec![OpCode::Constant as u8, 0, RETURN],
+                vec![Value::from(Obj::Fun(function))]
+            ),
+            "Invalid bytecode in <script> at offset 0: Constant can't load a function."
+        );
+    }
+}
@@ -1,113 +1,183 @@
 extern crate num;
 
-use crate::common::{random_color, FatPointer, Function, Obj, OpCode, Value};
-use crate::debug;
-use crate::hash_map::{Table, Entry};
-use crate::hasher::hash;
+use crate::common::{
+    self, random_color, BoundMethod, Class, Closure, FatPointer, Function, FunctionType, Instance,
+    List, Map, Native, NativeFn, Obj, OpCode, UpValueCell, Value,
+};
+use crate::bytecode;
+use crate::chunk;
+use crate::debug::{self, TraceOptions};
+use crate::disassembler;
+use crate::hash_map::Table;
+use crate::memory::{Heap, HeapObj};
 use crate::metrics;
+use crate::native;
 use crate::scanner::Scanner;
+use crate::verifier;
 use crate::{compiler, memory};
 use colored::{Color, Colorize};
+use std::fmt::Display;
+use std::io::{self, Write};
 
 const STACK_MAX: usize = 512;
 
-#[derive(Debug)]
 pub(crate) struct VM {
     ip: i32,
     stack: Vec<Option<Value>>,
     stack_top: usize,
-    table: Table<Value>,
     globals: Table<Value>,
     call_frames: Vec<Option<CallFrame>>,
     frame_count: usize,
+    // upvalues still pointing to a stack slot, sorted by slot.
+    open_up_values: Vec<*mut UpValueCell>,
+    // interned "init" used to look up class initializers
+    init_string: FatPointer,
+    heap: Heap,
+    // where `print` writes to
+    output: Box<dyn Write>,
+    // prints the value of top level expression statements, for the repl
+    echo: bool,
+    // trace options, read once per run instead of on every instruction
+    trace: TraceOptions,
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct CallFrame {
-    function: Function,
+    closure: *mut Closure,
     ip: usize,
     cf_stack_top: usize,
     color: Color,
 }
 
 impl CallFrame {
+    fn function(&self) -> &Function {
+        unsafe { &*(*self.closure).function }
+    }
+
+    fn up_values(&self) -> &[*mut UpValueCell] {
+        unsafe { &(*self.closure).up_values }
+    }
+
     fn print_name(&self) {
-        match &self.function.name {
-            Some(ptr) => {
-                let cf_name = memory::read_string(ptr.ptr, ptr.size);
-                println!(
-                    "{}",
-                    format!("****** CallFrame: {:?} ******", cf_name)
-                        .color(self.color)
-                        .bold()
-                );
+        let function = self.function();
+        let cf_name = match &function.name {
+            Some(ptr) if !matches!(function.func_type, FunctionType::Script) => {
+                memory::read_string(ptr.ptr, ptr.size)
             }
-            None => println!(
-                "{}",
-                format!("****** CallFrame: {:?} ******", "Main")
-                    .color(self.color)
-                    .bold()
-            ),
-        }
+            _ => "Main".to_string(),
+        };
+        debug::trace(
+            format!("****** CallFrame: {:?} ******", cf_name)
+                .color(self.color)
+                .bold()
+                .to_string(),
+        );
     }
 }
 
+#[derive(Debug)]
 pub enum InterpretResult {
-    InterpretOk,
-    InterpretCompileError,
-    InterpretRuntimeError,
+    Ok,
+    // every error the compiler reported
+    CompileError(Vec<String>),
+    RuntimeError(RuntimeError),
 }
 
-macro_rules! READ_BYTE {
-    ($self:ident, $frame:ident) => {
-        *{
-            let c = $frame.function.chunk.code.get($frame.ip as usize);
-            $frame.ip += 1;
-            c.unwrap()
+// error raised while running a script along with the call stack at that point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    // innermost frame first
+    pub trace: Vec<TraceLine>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceLine {
+    pub line: u32,
+    pub column: u32,
+    // `None` for top level code
+    pub function: Option<String>,
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        for trace_line in self.trace.iter() {
+            match &trace_line.function {
+                Some(name) => write!(
+                    f,
+                    "\n[line {}, column {}] in {}()",
+                    trace_line.line, trace_line.column, name
+                )?,
+                None => write!(
+                    f,
+                    "\n[line {}, column {}] in script",
+                    trace_line.line, trace_line.column
+                )?,
+            }
         }
-    };
+        Ok(())
+    }
 }
 
-macro_rules! READ_CONSTANT {
+macro_rules! READ_BYTE {
     ($self:ident, $frame:ident) => {{
-        let index = READ_BYTE!($self, $frame) as usize;
-        debug::info(format!("Reading constant from index: {:?}", index));
-        $frame.function.chunk.constants.values.get(index)
+        let c = *$frame
+            .function()
+            .chunk
+            .code
+            .get($frame.ip as usize)
+            .unwrap();
+        $frame.ip += 1;
+        c
+    }};
+}
+
+// operand of an indexed instruction, long variants of the opcode have a
+// long operand
+macro_rules! READ_INDEX {
+    ($self:ident, $frame:ident, $opcode:ident) => {{
+        let long = $opcode.is_some_and(OpCode::is_long);
+        let index = chunk::read_operand(&$frame.function().chunk.code[$frame.ip..], long);
+        $frame.ip += chunk::operand_width(long);
+        index
+    }};
+}
+
+macro_rules! READ_CONSTANT {
+    ($self:ident, $frame:ident, $opcode:ident) => {{
+        let index = READ_INDEX!($self, $frame, $opcode);
+        info!("Reading constant from index: {:?}", index);
+        $frame.function().chunk.constants.values.get(index)
     }};
 }
 
 macro_rules! BINARY_OP {
+    // for operations rust has no operator for
+    ($self:ident, |$left:ident, $right:ident| $result:expr) => {{
+        let peek_0 = $self.peek(0).as_ref().unwrap();
+        let peek_1 = $self.peek(1).as_ref().unwrap();
+        if !peek_0.is_number() || !peek_1.is_number() {
+            return $self.runtime_error("Operands must be numbers.");
+        }
+        let (right_val_popped, left_val_popped) = $self.pop_pair();
+        let $left = Into::<f64>::into(left_val_popped.as_ref().unwrap());
+        let $right = Into::<f64>::into(right_val_popped.as_ref().unwrap());
+        $self.push(Value::from($result));
+    }};
     ($self:ident, $op:tt) => {{
         let peek_0 = $self.peek(0).as_ref().unwrap();
         let peek_1 = $self.peek(1).as_ref().unwrap();
         if !peek_0.is_number() || !peek_1.is_number() {
-            $self.runtime_error("Expected two numbers for binary operation.");
-            return InterpretResult::InterpretRuntimeError;
+            return $self.runtime_error("Operands must be numbers.");
         }
-        let (right_val_popped, left_val_popped)  = $self.pop_pair();              
+        let (right_val_popped, left_val_popped) = $self.pop_pair();
         let left_float_val = Into::<f64>::into(left_val_popped.as_ref().unwrap());
         let right_float_val = Into::<f64>::into(right_val_popped.as_ref().unwrap());
         $self.push(Value::from(left_float_val $op right_float_val));
     }}
 }
 
-macro_rules! READ_CONSTANT_LONG {
-    ($self:ident, $frame:ident) => {{
-        let mut constant_index_bytes = [0, 0, 0, 0, 0, 0, 0, 0];
-        // our long constant index is usize which is 8 bytes
-        for i in 1..=8 {
-            constant_index_bytes[i - 1] = READ_BYTE!($self, $frame);
-        }
-        let constant_index = usize::from_ne_bytes(constant_index_bytes);
-        $frame
-            .function
-            .chunk
-            .constants
-            .values
-            .get(constant_index as usize)
-    }};
-}
-
 impl VM {
     pub(crate) fn init() -> VM {
         let mut local_stack = Vec::with_capacity(STACK_MAX);
@@ -119,22 +189,167 @@ impl VM {
         let mut call_frames: Vec<Option<CallFrame>> = Vec::new();
         call_frames.resize(512, None);
 
-        VM {
+        let mut vm = VM {
             ip: -1,
             stack: local_stack,
             stack_top: 0,
-            table: Table::init(10),
             globals: Table::init(10),
             call_frames,
             frame_count: 0,
+            open_up_values: Vec::new(),
+            init_string: FatPointer {
+                ptr: std::ptr::null_mut(),
+                size: 0,
+                hash: 0,
+            },
+            heap: Heap::init(),
+            output: Box::new(io::stdout()),
+            echo: false,
+            trace: TraceOptions::default(),
+        };
+        vm.init_string = vm.intern_string("init".to_string());
+        native::define_natives(&mut vm);
+        vm
+    }
+
+    // makes `function` available to lox as global `name`.
+    pub(crate) fn define_native(&mut self, name: &'static str, arity: u8, function: NativeFn) {
+        let native = Obj::Native(Native {
+            name,
+            arity,
+            function,
+        });
+        let name = self.intern_string(name.to_string());
+        self.globals.insert(name, Value::from(native));
+    }
+
+    // returns the string already known to the compiler and the vm if
+    // there is one so identical strings share the same pointer.
+    pub(crate) fn intern_string(&mut self, value: String) -> FatPointer {
+        self.collect_garbage_if_needed();
+        self.heap.intern(&value)
+    }
+
+    // makes `print` write to `output` instead of stdout.
+    pub(crate) fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    // makes the compiler print the value of bare expressions at the top level.
+    pub(crate) fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    // every global variable with its value, sorted by name.
+    pub(crate) fn globals(&self) -> Vec<(String, Value)> {
+        let mut globals: Vec<(String, Value)> = self
+            .globals
+            .iter()
+            .map(|(name, value)| (memory::read_string(name.ptr, name.size), value.clone()))
+            .collect();
+        globals.sort_by(|(left, _), (right, _)| left.cmp(right));
+        globals
+    }
+
+    // listings of the function or the methods of the class stored in global `name`.
+    pub(crate) fn disassemble(&self, name: &str) -> Result<Vec<String>, String> {
+        let value = self
+            .globals
+            .iter()
+            .find(|(key, _)| memory::read_string(key.ptr, key.size) == name)
+            .map(|(_, value)| value);
+        let listing = |closure: *mut Closure| {
+            disassembler::function_listing(unsafe { &*(*closure).function })
+        };
+        match value {
+            Some(Value::Obj(Obj::Closure(closure))) => Ok(vec![listing(*closure)]),
+            Some(Value::Obj(Obj::Class(class))) => {
+                let class = unsafe { &**class };
+                Ok(class
+                    .methods
+                    .iter()
+                    .filter_map(|(_, value)| match value {
+                        Value::Obj(Obj::Closure(closure)) => Some(listing(*closure)),
+                        _ => None,
+                    })
+                    .collect())
+            }
+            Some(_) => Err(format!("'{}' is not a function or class.", name)),
+            None => Err(format!("Undefined variable '{}'.", name)),
         }
     }
 
-    fn reset_stack(&mut self) {
-        self.stack_top = 0;
+    // collects before every allocation instead of only when the threshold is reached.
+    pub(crate) fn set_stress_gc(&mut self, stress: bool) {
+        self.heap.stress = stress;
+    }
+
+    // Every object allocated while running goes through here. A collection
+    // may happen before `value` is placed on the heap so whatever it points
+    // to has to be reachable from the roots, usually by staying on the stack.
+    fn allocate<T>(&mut self, value: T, kind: fn(*mut T) -> HeapObj) -> *mut T {
+        self.collect_garbage_if_needed();
+        self.heap.allocate(value, kind)
+    }
+
+    // for changes which may grow the storage of a list, map, class or instance
+    pub(crate) fn grow<R>(&mut self, object: HeapObj, change: impl FnOnce() -> R) -> R {
+        self.heap.grow(object, change)
+    }
+
+    fn collect_garbage_if_needed(&mut self) {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+    }
+
+    fn collect_garbage(&mut self) {
+        info!("-- gc begin");
+        let before = self.heap.bytes_allocated;
+
+        self.mark_roots();
+        self.heap.trace_references();
+        self.heap.sweep();
+
+        info!(
+            "-- gc end, collected {} bytes (from {} to {})",
+            before - self.heap.bytes_allocated,
+            before,
+            self.heap.bytes_allocated
+        );
+    }
+
+    // Collection only happens while the vm runs, by then the compiled script
+    // is on the stack and its frame so compiler constants are reached through
+    // the closures of the call frames.
+    fn mark_roots(&mut self) {
+        for value in self.stack[..self.stack_top].iter().flatten() {
+            self.heap.mark_value(value);
+        }
+        for frame in self.call_frames[..self.frame_count].iter().flatten() {
+            self.heap.mark_closure(frame.closure);
+        }
+        for up_value in self.open_up_values.iter() {
+            self.heap.mark_up_value(*up_value);
+        }
+        self.heap.mark_table(&self.globals);
+        self.heap.mark_string(&self.init_string);
+    }
+
+    pub(crate) fn new_string(&mut self, value: String) -> Value {
+        Value::from(Obj::from(self.intern_string(value)))
+    }
+
+    // `items` have to be reachable from the roots until the list exists
+    pub(crate) fn new_list(&mut self, items: Vec<Value>) -> Value {
+        Value::from(Obj::List(self.allocate(List::new(items), HeapObj::List)))
     }
 
     fn push(&mut self, value: Value) {
+        // grows for deep recursion or functions with many locals
+        if self.stack_top == self.stack.len() {
+            self.stack.push(None);
+        }
         self.stack[self.stack_top] = Option::Some(value);
         self.stack_top += 1;
     }
@@ -146,67 +361,109 @@ impl VM {
 
     fn pop_pair(&mut self) -> (&Option<Value>, &Option<Value>) {
         self.stack_top -= 2;
-        (self.stack.get(self.stack_top + 1).unwrap(), self.stack.get(self.stack_top).unwrap())
+        (
+            self.stack.get(self.stack_top + 1).unwrap(),
+            self.stack.get(self.stack_top).unwrap(),
+        )
     }
 
     fn peek(&self, distance: usize) -> &Option<Value> {
-        self.stack.get(self.stack_top - 1 - distance).unwrap()            
+        self.stack.get(self.stack_top - 1 - distance).unwrap()
     }
 
+    // the trace is filled in by `run` once the error reaches it.
+    fn runtime_error(&self, message: &str) -> InterpretResult {
+        InterpretResult::RuntimeError(RuntimeError {
+            message: message.to_string(),
+            trace: Vec::new(),
+        })
+    }
 
-    fn runtime_error(&self, message: &str) {
-        debug::info(format!("Runtime error: {:?}", message));
+    // one line per active frame, innermost first. Every frame's ip is past
+    // the instruction being executed so the location of the byte before it is used.
+    fn stack_trace(&self) -> Vec<TraceLine> {
+        self.call_frames[..self.frame_count]
+            .iter()
+            .rev()
+            .flatten()
+            .map(|frame| {
+                let function = frame.function();
+                let location = function.chunk.location_of(frame.ip.saturating_sub(1));
+                let function = match (&function.func_type, &function.name) {
+                    (FunctionType::Script, _) | (_, None) => None,
+                    (_, Some(name)) => Some(memory::read_string(name.ptr, name.size)),
+                };
+                TraceLine {
+                    line: location.line,
+                    column: location.column,
+                    function,
+                }
+            })
+            .collect()
+    }
+
+    fn reset_stack(&mut self) {
+        self.stack_top = 0;
+        self.frame_count = 0;
+        self.open_up_values.clear();
     }
 
     fn run(&mut self) -> InterpretResult {
+        self.trace = debug::options();
         let mut current_frame = self.call_frames[self.frame_count - 1]
             .as_ref()
             .unwrap()
             .clone();
+        let mut result = self.run_frames(&mut current_frame);
+        if let InterpretResult::RuntimeError(error) = &mut result {
+            // ip of the running frame is only kept up to date in our copy
+            if let Some(frame) = self.call_frames[self.frame_count - 1].as_mut() {
+                frame.ip = current_frame.ip;
+            }
+            error.trace = self.stack_trace();
+            self.reset_stack();
+        }
+        result
+    }
+
+    fn run_frames(&mut self, current_frame: &mut CallFrame) -> InterpretResult {
         loop {
             let instruction = READ_BYTE!(self, current_frame);
             let opcode = num::FromPrimitive::from_u8(instruction);
-            self.print_debug_info(&mut current_frame, &instruction, &opcode);
+            self.print_debug_info(current_frame, &opcode);
 
             match opcode {
                 Some(OpCode::Return) => {
-                    let is_last_frame = self.return_op(&mut current_frame);
+                    let is_last_frame = self.return_op(current_frame);
                     if is_last_frame {
-                        return InterpretResult::InterpretOk;
+                        return InterpretResult::Ok;
                     }
-                    current_frame = self.call_frames[self.frame_count - 1]
+                    *current_frame = self.call_frames[self.frame_count - 1]
                         .as_ref()
                         .unwrap()
-                        .clone();                    
+                        .clone();
                 }
                 Some(OpCode::Negate) => {
                     let value = self.peek(0).as_ref().unwrap();
                     if !value.is_number() {
-                        self.runtime_error("Expected number for Negate opcode!");
-                        return InterpretResult::InterpretRuntimeError;
+                        return self.runtime_error("Operand must be a number.");
                     }
                     let pop_val = self.pop().as_ref().unwrap();
                     let float_val = Into::<f64>::into(pop_val);
-                    self.push(Value::from(-1.0 * float_val));
+                    self.push(Value::from(-float_val));
                 }
                 Some(OpCode::Add) => {
-                    let value = self.peek(0).as_ref().unwrap();
-                    match value {
-                        Value::Obj(obj) => {
-                            if obj.is_string() {
-                                if self.peek(1).as_ref().unwrap().is_obj_string() {
-                                    let combined = self.concat();
-                                    self.push(combined);
-                                }
-                            } else {
-                                self.runtime_error("Expected String value on right side while adding to another string.");
-                                return InterpretResult::InterpretRuntimeError;
-                            }
+                    let right = self.peek(0).as_ref().unwrap();
+                    let left = self.peek(1).as_ref().unwrap();
+                    match (left, right) {
+                        (left, right) if left.is_obj_string() && right.is_obj_string() => {
+                            let combined = self.concat();
+                            self.push(combined);
                         }
-                        Value::Number(_value) => BINARY_OP!(self, +),
+                        (Value::Number(_), Value::Number(_)) => BINARY_OP!(self, +),
                         _ => {
-                            self.runtime_error("Unknown type detected for Add operation");
-                            return InterpretResult::InterpretOk;
+                            return self
+                                .runtime_error("Operands must be two numbers or two strings.");
                         }
                     }
                 }
@@ -219,19 +476,39 @@ impl VM {
                 Some(OpCode::Divide) => {
                     BINARY_OP!(self, /);
                 }
+                Some(OpCode::Modulo) => {
+                    BINARY_OP!(self, |left, right| common::modulo(left, right));
+                }
+                Some(OpCode::FloorDivide) => {
+                    BINARY_OP!(self, |left, right| (left / right).floor());
+                }
+                Some(OpCode::Power) => {
+                    BINARY_OP!(self, |left, right| left.powf(right));
+                }
                 Some(OpCode::Greater) => {
                     BINARY_OP!(self, >);
                 }
                 Some(OpCode::Less) => {
                     BINARY_OP!(self, <);
                 }
-                Some(OpCode::Equal) => {                    
+                Some(OpCode::GreaterEqual) => {
+                    BINARY_OP!(self, >=);
+                }
+                Some(OpCode::LessEqual) => {
+                    BINARY_OP!(self, <=);
+                }
+                Some(OpCode::Equal) => {
                     let (left, right) = self.pop_pair();
-                    let is_equal = left.as_ref().unwrap() == right.as_ref().unwrap();                
+                    let is_equal = left.as_ref().unwrap() == right.as_ref().unwrap();
                     self.push(Value::from(is_equal));
                 }
-                Some(OpCode::Constant) => {
-                    let constant = READ_CONSTANT!(self, current_frame);
+                Some(OpCode::NotEqual) => {
+                    let (left, right) = self.pop_pair();
+                    let is_equal = left.as_ref().unwrap() == right.as_ref().unwrap();
+                    self.push(Value::from(!is_equal));
+                }
+                Some(OpCode::Constant) | Some(OpCode::ConstantLong) => {
+                    let constant = READ_CONSTANT!(self, current_frame, opcode);
                     self.push((*constant.unwrap()).clone());
                 }
                 Some(OpCode::False) => {
@@ -243,96 +520,294 @@ impl VM {
                 Some(OpCode::Nil) => {
                     self.push(Value::Missing);
                 }
-                Some(OpCode::Not) => {                   
-                    let value = self.pop().as_ref().unwrap().clone();                     
+                Some(OpCode::Not) => {
+                    let value = self.pop().as_ref().unwrap().clone();
                     self.push(Value::from(self.is_falsey(value)));
                 }
-                Some(OpCode::ConstantLong) => {
-                    let constant = READ_CONSTANT_LONG!(self, current_frame);
-                    self.push((*constant.unwrap()).clone());
-                }
-                Some(OpCode::DefineGlobalVariable) => {
-                    let constant = READ_CONSTANT!(self, current_frame).unwrap().clone();
+                Some(OpCode::DefineGlobalVariable) | Some(OpCode::DefineGlobalVariableLong) => {
+                    let constant = READ_CONSTANT!(self, current_frame, opcode).unwrap().clone();
                     let variable_name = Into::<FatPointer>::into(&constant);
                     let value = self.peek(0).as_ref().unwrap();
-                    debug::info(format!(
-                        "DefineGlobalVariable: Define constant value: {:?}",
-                        value
-                    ));
+                    info!("DefineGlobalVariable: Define constant value: {:?}", value);
                     self.globals.insert(variable_name, value.clone());
                     self.pop();
                 }
                 Some(OpCode::Pop) => {
                     self.pop();
                 }
-                Some(OpCode::Closure) => {
-                    let constant = READ_CONSTANT!(self, current_frame).unwrap().clone();
-                    let function_obj = Into::<Obj>::into(&constant);
-                    let closure = Obj::Closure(Box::new(function_obj));
-                    self.push(Value::from(closure));
+                Some(OpCode::Closure) | Some(OpCode::ClosureLong) => {
+                    let constant = READ_CONSTANT!(self, current_frame, opcode).unwrap().clone();
+                    let function = Into::<*mut Function>::into(Into::<Obj>::into(&constant));
+                    let up_value_count = unsafe { &*function }.up_value_count;
+                    let mut up_values = Vec::with_capacity(up_value_count);
+                    for _ in 0..up_value_count {
+                        let is_local = READ_BYTE!(self, current_frame);
+                        let index = READ_INDEX!(self, current_frame, opcode);
+                        if is_local == 1 {
+                            up_values
+                                .push(self.capture_up_value(current_frame.cf_stack_top + index));
+                        } else {
+                            up_values.push(current_frame.up_values()[index]);
+                        }
+                    }
+                    let closure =
+                        self.allocate(Closure::new(function, up_values), HeapObj::Closure);
+                    self.push(Value::from(Obj::Closure(closure)));
+                }
+                Some(OpCode::GetUpValue) | Some(OpCode::GetUpValueLong) => {
+                    let slot = READ_INDEX!(self, current_frame, opcode);
+                    let value = match unsafe { &*current_frame.up_values()[slot] } {
+                        UpValueCell::Open(location) => self.stack[*location].clone().unwrap(),
+                        UpValueCell::Closed(value) => value.clone(),
+                    };
+                    self.push(value);
+                }
+                Some(OpCode::SetUpValue) | Some(OpCode::SetUpValueLong) => {
+                    let slot = READ_INDEX!(self, current_frame, opcode);
+                    let value = self.peek(0).as_ref().unwrap().clone();
+                    let cell = unsafe { &mut *current_frame.up_values()[slot] };
+                    match cell {
+                        UpValueCell::Open(location) => self.stack[*location] = Some(value),
+                        UpValueCell::Closed(_) => *cell = UpValueCell::Closed(value),
+                    }
+                }
+                Some(OpCode::CloseUpValue) => {
+                    self.close_up_values(self.stack_top - 1);
+                    self.pop();
                 }
                 Some(OpCode::Call) => {
                     let arg_count = READ_BYTE!(self, current_frame);
-                    let old_frame = current_frame.clone();                    
-                    if !self.execute_function(arg_count as usize, arg_count) {
-                        return InterpretResult::InterpretRuntimeError;
+                    // store caller state, calling a class without initializer
+                    // doesn't push a new frame so we continue with the same one.
+                    self.call_frames[self.frame_count - 1] = Some(current_frame.clone());
+                    if let Some(ret) = self.execute_function(arg_count as usize, arg_count) {
+                        return ret;
                     }
-                    current_frame = self.call_frames[self.frame_count - 1]
+                    *current_frame = self.call_frames[self.frame_count - 1]
                         .as_ref()
                         .unwrap()
                         .clone();
-                    self.call_frames[self.frame_count - 2] = Some(old_frame);
                 }
                 Some(OpCode::JumpIfFalse) => {
                     if self.is_falsey(self.peek(0).as_ref().unwrap().clone()) {
                         //current_frame.ip += offset as usize;
-                        current_frame = self.update_offset(current_frame, true);
+                        self.update_offset(current_frame, true, 2);
+                    } else {
+                        current_frame.ip += 2;
+                    }
+                }
+                Some(OpCode::JumpIfFalseLong) => {
+                    if self.is_falsey(self.peek(0).as_ref().unwrap().clone()) {
+                        self.update_offset(current_frame, true, 4);
                     } else {
-                        current_frame.ip = current_frame.ip + 2;
+                        current_frame.ip += 4;
                     }
                 }
                 Some(OpCode::Jump) => {
-                    current_frame = self.update_offset(current_frame, true);
+                    self.update_offset(current_frame, true, 2);
+                }
+                Some(OpCode::JumpLong) => {
+                    self.update_offset(current_frame, true, 4);
                 }
                 Some(OpCode::Loop) => {
-                    current_frame = self.update_offset(current_frame, false);
+                    self.update_offset(current_frame, false, 2);
+                }
+                Some(OpCode::LoopLong) => {
+                    self.update_offset(current_frame, false, 4);
                 }
-                Some(OpCode::GetLocalVariable) => {
-                    let b = READ_BYTE!(self, current_frame);
-                    let val = self.stack[current_frame.cf_stack_top + b as usize]
+                Some(OpCode::GetLocalVariable) | Some(OpCode::GetLocalVariableLong) => {
+                    let b = READ_INDEX!(self, current_frame, opcode);
+                    let val = self.stack[current_frame.cf_stack_top + b]
                         .clone()
                         .unwrap();
                     self.push(val.clone());
                 }
-                Some(OpCode::SetLocalVariable) => {
-                    let b = READ_BYTE!(self, current_frame);
-                    self.stack[current_frame.cf_stack_top + b as usize] = Some(self.peek(0).as_ref().unwrap().clone());
+                Some(OpCode::SetLocalVariable) | Some(OpCode::SetLocalVariableLong) => {
+                    let b = READ_INDEX!(self, current_frame, opcode);
+                    self.stack[current_frame.cf_stack_top + b] =
+                        Some(self.peek(0).as_ref().unwrap().clone());
                 }
-                Some(OpCode::GetGlobalVariable) => {
-                    let constant = READ_CONSTANT!(self, current_frame).unwrap().clone();
-                    debug::info(format!(
-                        "GetGlobalVariable: Read constant value: {:?}",
-                        constant
-                    ));
+                Some(OpCode::GetGlobalVariable) | Some(OpCode::GetGlobalVariableLong) => {
+                    let constant = READ_CONSTANT!(self, current_frame, opcode).unwrap().clone();
+                    info!("GetGlobalVariable: Read constant value: {:?}", constant);
                     let variable_name = Into::<FatPointer>::into(&constant);
                     if let Some(ret) = self.push_obj_value_to_stack(variable_name) {
                         return ret;
                     }
                 }
-                Some(OpCode::SetGlobalVariable) => {
-                    let constant = READ_CONSTANT!(self, current_frame).unwrap().clone();
+                Some(OpCode::SetGlobalVariable) | Some(OpCode::SetGlobalVariableLong) => {
+                    let constant = READ_CONSTANT!(self, current_frame, opcode).unwrap().clone();
                     let variable_name = Into::<FatPointer>::into(&constant);
                     if let Some(ret) = self.set_global_variable(variable_name) {
                         return ret;
                     }
                 }
                 Some(OpCode::Print) => {
-                    debug::print_value(self.pop().as_ref().unwrap(), true);
+                    let value = self.pop().as_ref().unwrap().clone();
+                    if writeln!(self.output, "{}", value).is_err() {
+                        return self.runtime_error("Unable to write output.");
+                    }
+                }
+                Some(OpCode::Class) | Some(OpCode::ClassLong) => {
+                    let constant = READ_CONSTANT!(self, current_frame, opcode).unwrap().clone();
+                    let class_name = Into::<FatPointer>::into(&constant);
+                    let class = self.allocate(Class::new(class_name), HeapObj::Class);
+                    self.push(Value::from(Obj::Class(class)));
+                }
+                Some(OpCode::Method) | Some(OpCode::MethodLong) => {
+                    let constant = READ_CONSTANT!(self, current_frame, opcode).unwrap().clone();
+                    let method_name = Into::<FatPointer>::into(&constant);
+                    let method = self.peek(0).as_ref().unwrap().clone();
+                    if let Some(Value::Obj(Obj::Class(class))) = self.peek(1) {
+                        let class = *class;
+                        self.grow(HeapObj::Class(class), || unsafe {
+                            (*class).methods.insert(method_name, method)
+                        });
+                    }
+                    self.pop();
+                }
+                Some(OpCode::Inherit) => {
+                    let superclass = match self.peek(1) {
+                        Some(Value::Obj(Obj::Class(superclass))) => unsafe { &**superclass },
+                        _ => {
+                            return self.runtime_error("Superclass must be a class.");
+                        }
+                    };
+                    // methods are copied down so later lookups never walk the hierarchy,
+                    // subclass methods are added afterwards and override these.
+                    if let Some(Value::Obj(Obj::Class(subclass))) = self.peek(0) {
+                        let subclass = *subclass;
+                        self.grow(HeapObj::Class(subclass), || {
+                            superclass
+                                .methods
+                                .add_all(unsafe { &mut (*subclass).methods })
+                        });
+                    }
+                    self.pop();
+                }
+                Some(OpCode::GetSuper) | Some(OpCode::GetSuperLong) => {
+                    let constant = READ_CONSTANT!(self, current_frame, opcode).unwrap().clone();
+                    let name = Into::<FatPointer>::into(&constant);
+                    let superclass = match self.pop() {
+                        Some(Value::Obj(Obj::Class(superclass))) => *superclass,
+                        _ => {
+                            return self.runtime_error("Superclass must be a class.");
+                        }
+                    };
+                    if let Some(ret) = self.bind_method(superclass, name) {
+                        return ret;
+                    }
+                }
+                Some(OpCode::GetProperty) | Some(OpCode::GetPropertyLong) => {
+                    let constant = READ_CONSTANT!(self, current_frame, opcode).unwrap().clone();
+                    let name = Into::<FatPointer>::into(&constant);
+                    let instance = match self.peek(0) {
+                        Some(Value::Obj(Obj::Instance(instance))) => unsafe { &**instance },
+                        _ => {
+                            return self.runtime_error("Only instances have properties.");
+                        }
+                    };
+                    match instance.fields.get(name.clone()).cloned() {
+                        Some(value) => {
+                            self.pop();
+                            self.push(value);
+                        }
+                        None => {
+                            if let Some(ret) = self.bind_method(instance.class, name) {
+                                return ret;
+                            }
+                        }
+                    }
+                }
+                Some(OpCode::SetProperty) | Some(OpCode::SetPropertyLong) => {
+                    let constant = READ_CONSTANT!(self, current_frame, opcode).unwrap().clone();
+                    let name = Into::<FatPointer>::into(&constant);
+                    let instance = match self.peek(1) {
+                        Some(Value::Obj(Obj::Instance(instance))) => *instance,
+                        _ => {
+                            return self.runtime_error("Only instances have fields.");
+                        }
+                    };
+                    let value = self.pop().as_ref().unwrap().clone();
+                    self.grow(HeapObj::Instance(instance), || unsafe {
+                        (*instance).fields.insert(name, value.clone())
+                    });
+                    // replace instance with the assigned value
+                    self.pop();
+                    self.push(value);
+                }
+                Some(OpCode::BuildList) | Some(OpCode::BuildListLong) => {
+                    let count = READ_INDEX!(self, current_frame, opcode);
+                    let items = self.stack[self.stack_top - count..self.stack_top]
+                        .iter()
+                        .map(|item| item.clone().unwrap())
+                        .collect();
+                    // items are popped only now so a collection can't free them
+                    let list = self.allocate(List::new(items), HeapObj::List);
+                    self.stack_top -= count;
+                    self.push(Value::from(Obj::List(list)));
+                }
+                Some(OpCode::BuildMap) | Some(OpCode::BuildMapLong) => {
+                    let count = READ_INDEX!(self, current_frame, opcode);
+                    let first = self.stack_top - 2 * count;
+                    let mut entries = Table::init((2 * count).max(8));
+                    // later entries win when a key is repeated
+                    for pair in self.stack[first..self.stack_top].chunks(2) {
+                        let key = pair[0].clone().unwrap();
+                        if let Err(message) = Map::check_key(&key) {
+                            return self.runtime_error(&message);
+                        }
+                        entries.insert(key, pair[1].clone().unwrap());
+                    }
+                    // entries are popped only now so a collection can't free them
+                    let map = self.allocate(Map::new(entries), HeapObj::Map);
+                    self.stack_top = first;
+                    self.push(Value::from(Obj::Map(map)));
+                }
+                Some(OpCode::GetIndex) => {
+                    let index = self.peek(0).as_ref().unwrap();
+                    let value = match self.peek(1) {
+                        Some(Value::Obj(Obj::List(list))) => {
+                            let list = unsafe { &**list };
+                            list.position(index, false)
+                                .map(|position| list.items[position].clone())
+                        }
+                        Some(Value::Obj(Obj::Map(map))) => unsafe { &**map }.get(index),
+                        _ => Err("Only lists and maps can be indexed.".to_string()),
+                    };
+                    match value {
+                        Ok(value) => {
+                            self.pop_pair();
+                            self.push(value);
+                        }
+                        Err(message) => return self.runtime_error(&message),
+                    }
+                }
+                Some(OpCode::SetIndex) => {
+                    let value = self.peek(0).as_ref().unwrap().clone();
+                    let index = self.peek(1).as_ref().unwrap().clone();
+                    let result = match self.peek(2).clone() {
+                        Some(Value::Obj(Obj::List(list))) => {
+                            let list = unsafe { &mut *list };
+                            list.position(&index, false)
+                                .map(|position| list.items[position] = value.clone())
+                        }
+                        Some(Value::Obj(Obj::Map(map))) => self.grow(HeapObj::Map(map), || {
+                            unsafe { &mut *map }.set(index, value.clone())
+                        }),
+                        _ => Err("Only lists and maps can be indexed.".to_string()),
+                    };
+                    if let Err(message) = result {
+                        return self.runtime_error(&message);
+                    }
+                    // replace the collection, index and value with the assigned value
+                    self.stack_top -= 3;
+                    self.push(value);
                 }
                 _ => {
-                    debug::info(format!("Stopping vm: {:?}", opcode));
-                    self.call_frames[self.frame_count - 1] = Some(current_frame);
-                    return InterpretResult::InterpretOk;
+                    info!("Stopping vm: {:?}", opcode);
+                    self.call_frames[self.frame_count - 1] = Some(current_frame.clone());
+                    return InterpretResult::Ok;
                 }
             }
         }
@@ -346,9 +821,8 @@ impl VM {
         if !self.globals.insert(variable_name.clone(), value.as_ref().unwrap().clone()) {
             self.globals.delete(variable_name.clone());
             let key = memory::read_string(ptr, size);
-            let message = format!("Unable to find value for key {:?}", key);
-            self.runtime_error(message.as_str());
-            return Some(InterpretResult::InterpretRuntimeError);
+            let message = format!("Undefined variable '{}'.", key);
+            return Some(self.runtime_error(message.as_str()));
         }
 
         None
@@ -357,54 +831,14 @@ impl VM {
     fn push_obj_value_to_stack(&mut self, variable_name: FatPointer) -> Option<InterpretResult> {
         let size = variable_name.size;
         let ptr = variable_name.ptr;
-        let value = self.get_variable_value(variable_name);
-        debug::info(format!(
-            "Found global value: {:?}",
-            value
-        ));
+        let value = self.get_variable_value(variable_name).cloned();
+        info!("Found global value: {:?}", value);
         match value {
-            Some(val) => match value {
-                Some(Value::Boolean(v)) => {
-                    debug::info(format!("Boolean value pushing to stack {:?}", v));
-                    self.push(val.clone());
-                }
-                Some(Value::Number(v)) => {
-                    debug::info(format!("Number value pushing to stack {:?}", v));
-                    self.push(val.clone());
-                }
-                Some(Value::Obj(obj)) => match obj {
-                    Obj::Str(ptr) => {
-                        let c_value = memory::read_string(ptr.ptr, ptr.size);
-                        debug::info(format!(
-                            "String Object value pushing to stack {:?}",
-                            c_value
-                        ));
-                        self.push(val.clone());
-                    }
-                    Obj::Fun(function) => {
-                        let function_name = function.name.as_ref().unwrap();
-                        let name = memory::read_string(function_name.ptr, function_name.size);
-                        debug::info(format!(
-                            "Function Object value pushing to stack {:?} with name: {:?}",
-                            function, name
-                        ));
-                        self.push(val.clone());
-                    }
-                    _ => {
-                        debug::info(format!("Unknown object pushing to stack {:?}", obj));
-                        self.push(val.clone());
-                    }
-                },
-                _ => {
-                    debug::info(format!("Unknown value pushing to stack"));
-                    self.push(val.clone());
-                }
-            },
+            Some(val) => self.push(val),
             None => {
                 let key = memory::read_string(ptr, size);
-                let message = format!("Unable to find value for key {:?}", key);
-                self.runtime_error(message.as_str());
-                return Some(InterpretResult::InterpretRuntimeError);
+                let message = format!("Undefined variable '{}'.", key);
+                return Some(self.runtime_error(message.as_str()));
             }
         }
         None
@@ -413,83 +847,152 @@ impl VM {
     fn print_debug_info(
         &mut self,
         current_frame: &mut CallFrame,
-        instruction: &u8,
         opcode: &Option<OpCode>,
     ) {
-        current_frame.print_name();
-        if !matches!(opcode, None) {
-            if debug::PRINT_STACK {
-                debug::info(format!("##### Stack[Start] ###### \n"));
-                for i in 0..self.stack.len() {
-                    print!("[{:?}] ", self.stack[i]);
-                }
-                debug::info(format!("\n\n ##### Stack[End] ######"));
+        if self.trace.exec {
+            current_frame.print_name();
+        }
+        if opcode.is_some() {
+            if self.trace.stack {
+                let values: Vec<String> = self.stack[..self.stack_top]
+                    .iter()
+                    .flatten()
+                    .map(|value| format!("[{}]", value))
+                    .collect();
+                debug::trace(format!("stack: {}", values.join(" ")));
             }
 
-            current_frame
-                .function
-                .chunk
-                .handle_instruction(&instruction, (current_frame.ip - 1) as usize);
+            if self.trace.exec {
+                let chunk = &current_frame.function().chunk;
+                let instruction = chunk.decode(current_frame.ip - 1);
+                debug::trace(disassembler::instruction_listing(chunk, &instruction));
+            }
         }
     }
 
     fn return_op(&mut self, current_frame: &mut CallFrame) -> bool {
         let result = self.pop().as_ref().unwrap().clone();
+        // locals of the returning function which are captured by closures
+        // have to be moved off the stack before the frame is discarded.
+        self.close_up_values(current_frame.cf_stack_top);
         self.frame_count -= 1;
 
         if self.frame_count == 0 {
-            // @todo check if we need this pop.
-            //self.pop();
+            // pop the script closure so repeated `interpret` calls start
+            // from an empty stack.
+            self.pop();
             return true;
         }
         // + 1 for the first stack entry
         self.stack_top = current_frame.cf_stack_top;
-        debug::info(format!("Pushing return value to stack: {:?}", result));
+        info!("Pushing return value to stack: {:?}", result);
         self.push(result);
         false
     }
 
-    fn execute_function(&mut self, distance: usize, arg_count: u8) -> bool {
-        let callee = self.peek(distance);
-        if callee.as_ref().unwrap().is_obj() {
-            let obj = Into::<Obj>::into(callee.as_ref().unwrap());
+    fn execute_function(&mut self, distance: usize, arg_count: u8) -> Option<InterpretResult> {
+        let callee = self.peek(distance).as_ref().unwrap().clone();
+        if callee.is_obj() {
+            let obj = Into::<Obj>::into(&callee);
             match obj {
-                Obj::Fun(function) => {
-                    if function.arity != arg_count {
-                        self.runtime_error(
-                            format!(
-                                "Expected: {:?} arguments but received: {:?}",
-                                function.arity, arg_count
-                            )
-                            .as_str(),
-                        );
-                    }
-                    self.create_call_frame(function, arg_count);
-                    return true;
-                }
-                Obj::Closure(obj) => {
-                    let function = Into::<Function>::into(*obj);
-                    if function.arity != arg_count {
-                        self.runtime_error(
-                            format!(
-                                "Expected: {:?} arguments but received: {:?}",
-                                function.arity, arg_count
-                            )
-                            .as_str(),
-                        );
-                    }
-                    self.create_call_frame(function, arg_count);
-                    return true;
+                Obj::Closure(closure) => {
+                    return self.call_closure(closure, arg_count);
+                }
+                Obj::Class(class) => {
+                    // the class in callee slot is replaced by new instance
+                    // which becomes `this` for the initializer.
+                    let instance = self.allocate(Instance::new(class), HeapObj::Instance);
+                    self.stack[self.stack_top - distance - 1] =
+                        Some(Value::from(Obj::Instance(instance)));
+                    let initializer = unsafe { (*class).methods.get(self.init_string.clone()) };
+                    return match initializer {
+                        Some(Value::Obj(Obj::Closure(closure))) => {
+                            let closure = *closure;
+                            self.call_closure(closure, arg_count)
+                        }
+                        _ if arg_count != 0 => Some(self.runtime_error(
+                            format!("Expected 0 arguments but got {}.", arg_count).as_str(),
+                        )),
+                        _ => None,
+                    };
+                }
+                Obj::BoundMethod(bound_method) => {
+                    let bound_method = unsafe { &*bound_method };
+                    self.stack[self.stack_top - distance - 1] = Some(bound_method.receiver.clone());
+                    return self.call_closure(bound_method.method, arg_count);
+                }
+                Obj::Native(native) => {
+                    return self.call_native(native, arg_count);
                 }
                 _ => (),
             }
         }
-        println!("Expected function but instead got: {:?}", callee);
-        self.runtime_error("Can only execute function");
-        false
+        info!("Expected function but instead got: {:?}", callee);
+        Some(self.runtime_error("Can only call functions and classes."))
+    }
+
+    fn call_closure(&mut self, closure: *mut Closure, arg_count: u8) -> Option<InterpretResult> {
+        let function = unsafe { &*(*closure).function };
+        if function.arity != arg_count {
+            let message = format!(
+                "Expected {} arguments but got {}.",
+                function.arity, arg_count
+            );
+            return Some(self.runtime_error(message.as_str()));
+        }
+        if self.frame_count == self.call_frames.len() {
+            return Some(self.runtime_error("Stack overflow."));
+        }
+        self.create_call_frame(closure, arg_count);
+        None
+    }
+
+    // natives run directly on the caller's frame, arguments and the
+    // native itself are replaced by the result.
+    fn call_native(&mut self, native: Native, arg_count: u8) -> Option<InterpretResult> {
+        if native.arity != arg_count {
+            let message = format!(
+                "{} expected {} arguments but got {}.",
+                native.name, native.arity, arg_count
+            );
+            return Some(self.runtime_error(message.as_str()));
+        }
+        let args: Vec<Value> = self.stack[self.stack_top - arg_count as usize..self.stack_top]
+            .iter()
+            .map(|arg| arg.clone().unwrap())
+            .collect();
+        match (native.function)(self, &args) {
+            Ok(result) => {
+                self.stack_top -= arg_count as usize + 1;
+                self.push(result);
+                None
+            }
+            Err(message) => Some(self.runtime_error(message.as_str())),
+        }
+    }
+
+    // replaces the instance on top of the stack with method `name` of its class bound to it.
+    fn bind_method(&mut self, class: *mut Class, name: FatPointer) -> Option<InterpretResult> {
+        let method = unsafe { (*class).methods.get(name.clone()) };
+        match method {
+            Some(Value::Obj(Obj::Closure(closure))) => {
+                let closure = *closure;
+                // receiver stays on the stack until the bound method exists
+                let receiver = self.peek(0).as_ref().unwrap().clone();
+                let bound_method =
+                    self.allocate(BoundMethod::new(receiver, closure), HeapObj::BoundMethod);
+                self.pop();
+                self.push(Value::from(Obj::BoundMethod(bound_method)));
+                None
+            }
+            _ => {
+                let name = memory::read_string(name.ptr, name.size);
+                Some(self.runtime_error(format!("Undefined property '{}'.", name).as_str()))
+            }
+        }
     }
 
-    fn create_call_frame(&mut self, function: Function, arg_count: u8) {
+    fn create_call_frame(&mut self, closure: *mut Closure, arg_count: u8) {
         let mut cf_stack_top = 0;
         if self.stack_top > 0 {
             /*
@@ -499,91 +1002,298 @@ impl VM {
              * one slot earlier to align them with the arguments.
              * -1 is for name of the function
              */
-            cf_stack_top = self.stack_top - (arg_count as usize) - (1 as usize);
+            cf_stack_top = self.stack_top - (arg_count as usize) - 1;
         };
 
         let call_frame = CallFrame {
-            function,
+            closure,
             ip: 0, //@todo check if this value should be 0 or not
             cf_stack_top,
             color: random_color(),
         };
         self.call_frames[self.frame_count] = Some(call_frame);
-        println!("Callframes SIZE: {:?}", self.call_frames.iter().filter(|cf| matches!(cf, Some(_))).count());
         self.frame_count += 1;
     }
 
-    fn update_offset(&self, mut current_frame: CallFrame, add: bool) -> CallFrame {
-        let offset_bytes: [u8; 2] = [
-            current_frame.function.chunk.code[(current_frame.ip + 1) as usize],
-            current_frame.function.chunk.code[(current_frame.ip) as usize],
-        ];
-        current_frame.ip = current_frame.ip + 2;
-        // adding 2 because we read offset bytes
-        let offset = u16::from_ne_bytes(offset_bytes);
+    // reuses the upvalue if some other closure already captured the same slot
+    // so all of them see the same variable.
+    fn capture_up_value(&mut self, location: usize) -> *mut UpValueCell {
+        let mut insert_at = self.open_up_values.len();
+        for (i, up_value) in self.open_up_values.iter().enumerate() {
+            if let UpValueCell::Open(existing) = unsafe { &**up_value } {
+                if *existing == location {
+                    return *up_value;
+                }
+                if *existing > location {
+                    insert_at = i;
+                    break;
+                }
+            }
+        }
+        let up_value = self.allocate(UpValueCell::Open(location), HeapObj::UpValue);
+        self.open_up_values.insert(insert_at, up_value);
+        up_value
+    }
+
+    // moves every open upvalue pointing at or above `last` slot into its cell.
+    fn close_up_values(&mut self, last: usize) {
+        while let Some(up_value) = self.open_up_values.last() {
+            let cell = unsafe { &mut **up_value };
+            let location = match cell {
+                UpValueCell::Open(location) => *location,
+                UpValueCell::Closed(_) => break,
+            };
+            if location < last {
+                break;
+            }
+            *cell = UpValueCell::Closed(self.stack[location].clone().unwrap());
+            self.open_up_values.pop();
+        }
+    }
+
+    // the offset is `width` bytes, most significant first
+    fn update_offset(&self, current_frame: &mut CallFrame, add: bool, width: usize) {
+        let offset = current_frame
+            .function()
+            .chunk
+            .get_offset(current_frame.ip - 1, width);
+        // skipping the offset bytes we just read
+        current_frame.ip += width;
         if add {
-            current_frame.ip += offset as usize;
+            current_frame.ip += offset;
         } else {
-            current_frame.ip -= offset as usize;
+            current_frame.ip -= offset;
         }
-
-        current_frame
     }
 
     fn get_variable_value(&self, variable_name: FatPointer) -> Option<&Value> {
-        debug::info(format!(
-            "Get variable value for key: {:?}",
-            variable_name
-        ));
+        info!("Get variable value for key: {:?}", variable_name);
         self.globals.get(variable_name)
     }
 
-    fn is_equal(&self, left: Value, right: Value) -> bool {
-        left == right
-    }
-
-    // we are treating nil as false    
+    // we are treating nil as false
     fn is_falsey(&self, value: Value) -> bool {
         value.is_missing() || (value.is_boolean() && !Into::<bool>::into(&value))
     }
 
+    // the result is interned like every other string so it compares
+    // equal to a literal with the same content.
     fn concat(&mut self) -> Value {
-        let(second_val, first_val) = self.pop_pair();
+        let second = Into::<FatPointer>::into(self.peek(0).as_ref().unwrap());
+        let first = Into::<FatPointer>::into(self.peek(1).as_ref().unwrap());
+        let mut combined = memory::read_string(first.ptr, first.size);
+        combined.push_str(&memory::read_string(second.ptr, second.size));
+        // operands are popped only now so a collection can't free them
+        let fat_ptr = self.intern_string(combined);
+        self.pop_pair();
+        Value::from(Obj::from(fat_ptr))
+    }
 
-        let second = Into::<FatPointer>::into(second_val.as_ref().unwrap());
-        let first = Into::<FatPointer>::into(first_val.as_ref().unwrap());
+    pub(crate) fn interpret(&mut self, source: String) -> InterpretResult {
+        match self.compile(source) {
+            Ok(function) => self.run_script(function),
+            Err(errors) => InterpretResult::CompileError(errors),
+        }
+    }
 
-        let ptr = memory::allocate::<String>();
-        memory::copy(first.ptr, ptr, first.size, 0);
-        memory::copy(second.ptr, ptr, second.size, first.size);
+    // runs a script compiled ahead of time, see `bytecode`.
+    pub(crate) fn interpret_bytecode(&mut self, bytes: &[u8]) -> InterpretResult {
+        match self.load_bytecode(bytes) {
+            Ok(function) => self.run_script(function),
+            Err(error) => InterpretResult::CompileError(vec![error]),
+        }
+    }
 
-        let hash_value = hash(memory::read_string(ptr, first.size + second.size).as_str());
-        Value::from(Obj::from(FatPointer {
-            ptr,
-            size: (first.size + second.size),
-            hash: hash_value,
-        }))
+    // reads and verifies a script compiled ahead of time without running it.
+    pub(crate) fn load_bytecode(&mut self, bytes: &[u8]) -> Result<Function, String> {
+        let function = bytecode::read(bytes, &mut self.heap)?;
+        verifier::verify(&function)?;
+        Ok(function)
     }
 
-    pub(crate) fn interpret<'m>(&mut self, source: String) -> InterpretResult {
+    // returns the top level function of `source` without running it.
+    pub(crate) fn compile(&mut self, source: String) -> Result<Function, Vec<String>> {
         let chars: Vec<char> = source.chars().collect();
         let scanner = Scanner::init(0, 0, chars);
 
-        let mut compiler = compiler::Compiler::init(scanner, &mut self.table);
+        let mut compiler = compiler::Compiler::init(scanner, &mut self.heap);
+        compiler.set_echo(self.echo);
 
-        let (had_error, function_obj) = metrics::record("Compiler time".to_string(), || {
-            compiler.compile(source.clone())
-        });
+        metrics::record("Compiler time".to_string(), || compiler.compile(source.clone()))
+    }
 
-        if had_error {
-            return InterpretResult::InterpretCompileError;
-        }
+    fn run_script(&mut self, function: Function) -> InterpretResult {
         self.ip = 0;
+        info!("Main function: {:?}", function);
+        // the function roots its constants until the closure replaces it
+        let function = self.heap.allocate(function, HeapObj::Function);
+        self.push(Value::from(Obj::Fun(function)));
+        let closure = self.allocate(Closure::new(function, Vec::new()), HeapObj::Closure);
+        self.pop();
+        self.push(Value::from(Obj::Closure(closure)));
+        self.create_call_frame(closure, 0);
+        let result = metrics::record("VM run time".to_string(), || self.run());
+        // buffered output has to be out before the caller exits
+        let _ = self.output.flush();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox_tests::SharedOutput;
+
+    fn run(vm: &mut VM, source: String) {
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.stack_top, 0);
+    }
+
+    fn global(vm: &mut VM, name: &str) -> Value {
+        let key = vm.intern_string(name.to_string());
+        vm.globals.get(key).cloned().unwrap()
+    }
+
+    #[test]
+    fn can_concat_and_print_long_strings() {
+        let mut vm = VM::init();
+        let output = SharedOutput::default();
+        vm.set_output(Box::new(output.clone()));
+        let first = "a".repeat(100);
+        let second = "b".repeat(300);
+        run(
+            &mut vm,
+            format!("var s = \"{}\" + \"{}\"; print s;", first, second),
+        );
+        let combined = first + &second;
+        assert_eq!(global(&mut vm, "s").to_string(), combined);
+        assert_eq!(output.contents(), format!("{}\n", combined));
+    }
+
+    #[test]
+    fn print_writes_lox_formatted_values_to_output() {
+        let mut vm = VM::init();
+        let output = SharedOutput::default();
+        vm.set_output(Box::new(output.clone()));
+        run(
+            &mut vm,
+            "fun f() {} class C {} print 3; print 2.5; print nil; print true; \
+             print \"raw\"; print f; print C; print C(); print clock;"
+                .to_string(),
+        );
+        assert_eq!(
+            output.contents(),
+            "3\n2.5\nnil\ntrue\nraw\n<fn f>\nC\nC instance\n<native fn>\n"
+        );
+    }
+
+    #[test]
+    fn concatenated_strings_are_interned() {
+        let mut vm = VM::init();
+        run(&mut vm, "var same = \"a\" + \"b\" == \"ab\";".to_string());
+        assert!(global(&mut vm, "same") == Value::from(true));
+    }
+
+    #[test]
+    fn runtime_error_has_trace_of_every_frame() {
+        let mut vm = VM::init();
+        let source = "fun inner() {\n  return 1 - nil;\n}\nfun outer() { inner(); }\nouter();";
+        let error = match vm.interpret(source.to_string()) {
+            InterpretResult::RuntimeError(error) => error,
+            result => panic!("Expected runtime error but got {:?}", result),
+        };
+        assert_eq!(error.message, "Operands must be numbers.");
+        assert_eq!(
+            error.to_string(),
+            "Operands must be numbers.\n[line 2, column 12] in inner()\n\
+             [line 4, column 20] in outer()\n[line 5, column 6] in script"
+        );
+        // the vm is usable again after the stack is reset
+        assert_eq!(vm.stack_top, 0);
+        run(&mut vm, "var after = 1;".to_string());
+    }
+
+    #[test]
+    fn compile_errors_have_the_column_of_the_token() {
+        let mut vm = VM::init();
+        // the string spans two lines, columns count from where the line starts
+        let source = "var s = \"a\nb\";\n  print s +;";
+        match vm.interpret(source.to_string()) {
+            InterpretResult::CompileError(errors) => assert_eq!(
+                errors,
+                vec!["[line 3, column 12] Error at ';': Expect expression"]
+            ),
+            result => panic!("Expected compile error but got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn can_grow_string_in_loop_with_stress_gc() {
+        let mut vm = VM::init();
+        vm.set_stress_gc(true);
+        run(
+            &mut vm,
+            "var s = \"\"; for (var i = 0; i < 50; i = i + 1) { s = s + \"0123456789\"; }"
+                .to_string(),
+        );
+        assert_eq!(global(&mut vm, "s").to_string(), "0123456789".repeat(50));
+    }
 
-        self.push(Value::from(Obj::Closure(Box::new(function_obj.clone()))));
-        let function = Into::<Function>::into(function_obj);
-        debug::info(format!("Main function: {:?}", function.clone()));
-        self.create_call_frame(function, 0);
-        metrics::record("VM run time".to_string(), || self.run())
+    #[test]
+    fn echoes_top_level_expressions_across_entries() {
+        let mut vm = VM::init();
+        let output = SharedOutput::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.set_echo(true);
+        run(&mut vm, "var a = \"a\";".to_string());
+        run(&mut vm, "fun f() { a + \"b\"; }".to_string());
+        run(&mut vm, "f(); a + \"b\"".to_string());
+        run(&mut vm, "if (true) a; { a; }".to_string());
+        assert_eq!(output.contents(), "nil\nab\n");
+        assert_eq!(vm.globals()[0].0, "a");
+    }
+
+    #[test]
+    fn disassembles_functions_and_methods_of_globals() {
+        let mut vm = VM::init();
+        let output = SharedOutput::default();
+        vm.set_output(Box::new(output.clone()));
+        run(
+            &mut vm,
+            "fun f() {} class C { m() {} } var n = 1;".to_string(),
+        );
+
+        let listings = vm.disassemble("f").unwrap();
+        assert_eq!(listings.len(), 1);
+        assert!(listings[0].starts_with("== f =="));
+        let listings = vm.disassemble("C").unwrap();
+        assert_eq!(listings.len(), 1);
+        assert!(listings[0].starts_with("== m =="));
+        assert_eq!(
+            vm.disassemble("n").unwrap_err(),
+            "'n' is not a function or class."
+        );
+        assert_eq!(output.contents(), "");
+    }
+
+    #[test]
+    fn does_not_echo_break_and_continue() {
+        let mut vm = VM::init();
+        vm.set_echo(true);
+        match vm.interpret("break;".to_string()) {
+            InterpretResult::CompileError(errors) => assert_eq!(
+                errors,
+                vec!["[line 1, column 1] Error at 'break': Can't use 'break' outside of a loop."]
+            ),
+            result => panic!("Expected compile error but got {:?}", result),
+        }
+        let output = SharedOutput::default();
+        vm.set_output(Box::new(output.clone()));
+        run(
+            &mut vm,
+            "for (var i = 0; i < 3; i = i + 1) { if (i == 1) continue; if (i == 2) break; print i; }"
+                .to_string(),
+        );
+        assert_eq!(output.contents(), "0\n");
     }
 }